@@ -0,0 +1,67 @@
+use std::fs::File;
+use std::io::{prelude::*, SeekFrom};
+
+use super::ciso::{is_compressed, CSOReader};
+
+/// Storage underneath an `ISO`: either a plain image or a compressed one
+/// that is decoded on the fly. Compressed images are read-only.
+pub enum BlockDevice<R = File> {
+    Raw(R),
+    Compressed(CSOReader<R>),
+}
+
+impl<R: Read + Seek> BlockDevice<R> {
+    /// Picks the right decoder by looking at the start of the stream.
+    pub fn open(mut fp: R) -> std::io::Result<Self> {
+        if is_compressed(&mut fp)? {
+            Ok(BlockDevice::Compressed(CSOReader::new(fp)?))
+        } else {
+            Ok(BlockDevice::Raw(fp))
+        }
+    }
+}
+
+impl<R: std::fmt::Debug> std::fmt::Debug for BlockDevice<R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlockDevice::Raw(fp) => f.debug_tuple("Raw").field(fp).finish(),
+            BlockDevice::Compressed(cso) => f.debug_tuple("Compressed").field(&cso.header).finish(),
+        }
+    }
+}
+
+impl<R: Read + Seek> Read for BlockDevice<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            BlockDevice::Raw(fp) => fp.read(buf),
+            BlockDevice::Compressed(cso) => cso.read(buf),
+        }
+    }
+}
+
+impl<R: Read + Seek> Seek for BlockDevice<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            BlockDevice::Raw(fp) => fp.seek(pos),
+            BlockDevice::Compressed(cso) => cso.seek(pos),
+        }
+    }
+}
+
+impl<R: Write> Write for BlockDevice<R> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            BlockDevice::Raw(fp) => fp.write(buf),
+            BlockDevice::Compressed(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Compressed images are read-only",
+            )),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            BlockDevice::Raw(fp) => fp.flush(),
+            BlockDevice::Compressed(_) => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::io::prelude::*;
+use std::path::Path;
+
+use md5::Md5;
+use sha1::{Digest, Sha1};
+
+/// Digests of a whole image, as written before any CSO/ZSO compression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksums {
+    pub crc32: u32,
+    pub md5: String,
+    pub sha1: String,
+}
+
+impl std::fmt::Display for Checksums {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "CRC32 {:08x}, MD5 {}, SHA-1 {}",
+            self.crc32, self.md5, self.sha1
+        )
+    }
+}
+
+/// Passes everything through to `inner` while hashing it, so the output can
+/// be checked without reading it back.
+pub struct HashingWriter<W> {
+    inner: W,
+    crc32: crc32fast::Hasher,
+    md5: Md5,
+    sha1: Sha1,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            crc32: crc32fast::Hasher::new(),
+            md5: Md5::new(),
+            sha1: Sha1::new(),
+        }
+    }
+    pub fn finish(self) -> (W, Checksums) {
+        let checksums = Checksums {
+            crc32: self.crc32.finalize(),
+            md5: format!("{:x}", self.md5.finalize()),
+            sha1: format!("{:x}", self.sha1.finalize()),
+        };
+        (self.inner, checksums)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.crc32.update(&buf[0..written]);
+        self.md5.update(&buf[0..written]);
+        self.sha1.update(&buf[0..written]);
+        Ok(written)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Expected output checksums, one line per source variant and layout:
+///
+/// ```text
+/// <variant> <packed|preserve> <crc32> <md5> <sha1>
+/// ```
+///
+/// A file without any entries is an error, since nothing could be checked.
+pub fn load_expected(
+    path: &Path,
+    variant: &str,
+    layout: &str,
+) -> std::io::Result<Option<Checksums>> {
+    let text = std::fs::read_to_string(path)?;
+    let mut entries = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let crc32 = fields.get(2).and_then(|x| u32::from_str_radix(x, 16).ok());
+        let (crc32, md5, sha1) = match (fields.len(), crc32) {
+            (5, Some(crc32)) => (crc32, fields[3], fields[4]),
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "{}:{}: expected <variant> <packed|preserve> <crc32> <md5> <sha1>",
+                        path.display(),
+                        i + 1
+                    ),
+                ))
+            }
+        };
+        entries += 1;
+        if fields[0] == variant && fields[1] == layout {
+            return Ok(Some(Checksums {
+                crc32,
+                md5: md5.to_ascii_lowercase(),
+                sha1: sha1.to_ascii_lowercase(),
+            }));
+        }
+    }
+    if entries == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "{} has no checksums, so the output could not be verified",
+                path.display()
+            ),
+        ));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expected_checksums() {
+        let path =
+            std::env::temp_dir().join(format!("patcher-{}-expected.txt", std::process::id()));
+        std::fs::write(&path, "# comments only\n").unwrap();
+        assert!(load_expected(&path, "NPJH50581", "packed").is_err());
+
+        let md5 = "A".repeat(32);
+        let sha1 = "B".repeat(40);
+        std::fs::write(
+            &path,
+            format!("NPJH50581 packed 0badf00d {} {}\n", md5, sha1),
+        )
+        .unwrap();
+        let expected = load_expected(&path, "NPJH50581", "packed").unwrap();
+        assert_eq!(
+            expected,
+            Some(Checksums {
+                crc32: 0x0badf00d,
+                md5: md5.to_ascii_lowercase(),
+                sha1: sha1.to_ascii_lowercase(),
+            })
+        );
+        assert_eq!(load_expected(&path, "NPJH50581", "preserve").unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
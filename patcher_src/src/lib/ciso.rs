@@ -0,0 +1,499 @@
+use std::io::{prelude::*, SeekFrom};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use super::util::read_string_n;
+
+/// Set on an index entry when the block is stored uncompressed (CSO v1, ZSO)
+/// or compressed with LZ4 (CSO v2).
+const INDEX_FLAG: u32 = 0x8000_0000;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CSOFormat {
+    /// CISO, deflate compressed. Version 2 blocks may also be LZ4.
+    CSO,
+    /// ZISO, LZ4 compressed.
+    ZSO,
+}
+
+#[derive(Debug)]
+pub struct CSOHeader {
+    pub format: CSOFormat,
+    pub total_bytes: u64,
+    pub block_size: u32,
+    pub version: u8,
+    pub align: u8,
+}
+
+impl CSOHeader {
+    pub fn read<R: Read + Seek>(read: &mut R) -> std::io::Result<Self> {
+        let format = match read_string_n(read, 4)?.as_str() {
+            "CISO" => CSOFormat::CSO,
+            "ZISO" => CSOFormat::ZSO,
+            magic => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Unknown compressed image magic {:?}", magic),
+                ))
+            }
+        };
+        let header_size = read.read_u32::<LittleEndian>()?;
+        let total_bytes = read.read_u64::<LittleEndian>()?;
+        let block_size = read.read_u32::<LittleEndian>()?;
+        let version = read.read_u8()?;
+        let align = read.read_u8()?;
+        read.seek(SeekFrom::Current(2))?;
+        if version >= 2 && header_size != 0x18 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unexpected header size {:#x}", header_size),
+            ));
+        }
+        if block_size == 0 || !block_size.is_multiple_of(2048) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unsupported block size {}", block_size),
+            ));
+        }
+        Ok(Self {
+            format,
+            total_bytes,
+            block_size,
+            version,
+            align,
+        })
+    }
+    pub fn num_blocks(&self) -> usize {
+        self.total_bytes.div_ceil(self.block_size as u64) as usize
+    }
+}
+
+/// Settings for writing a compressed image.
+#[derive(Debug, Clone)]
+pub struct CSOOptions {
+    pub format: CSOFormat,
+    /// Uncompressed bytes per block, a multiple of 2048.
+    pub block_size: u32,
+    /// Deflate level, 0-9. LZ4 has no levels, so ZSO ignores it.
+    pub level: u32,
+}
+
+impl Default for CSOOptions {
+    fn default() -> Self {
+        Self {
+            format: CSOFormat::CSO,
+            block_size: 2048,
+            level: 9,
+        }
+    }
+}
+
+/// Returns true if `read` starts with a CISO or ZISO header. The position is restored.
+pub fn is_compressed<R: Read + Seek>(read: &mut R) -> std::io::Result<bool> {
+    let curr = read.stream_position()?;
+    read.seek(SeekFrom::Start(0))?;
+    let mut magic = [0u8; 4];
+    let res = match read.read_exact(&mut magic) {
+        Ok(()) => &magic == b"CISO" || &magic == b"ZISO",
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => false,
+        Err(e) => return Err(e),
+    };
+    read.seek(SeekFrom::Start(curr))?;
+    Ok(res)
+}
+
+/// Decodes a CISO (v1/v2) or ZISO image, presenting the uncompressed
+/// contents as a plain `Read + Seek` stream.
+pub struct CSOReader<R> {
+    inner: R,
+    pub header: CSOHeader,
+    index: Vec<u32>,
+    block: Vec<u8>,
+    compressed: Vec<u8>,
+    cached: Option<usize>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> CSOReader<R> {
+    pub fn new(mut inner: R) -> std::io::Result<Self> {
+        inner.seek(SeekFrom::Start(0))?;
+        let header = CSOHeader::read(&mut inner)?;
+        let mut index = vec![0u32; header.num_blocks() + 1];
+        inner.read_u32_into::<LittleEndian>(&mut index)?;
+        // The end of the last block is never 0; `CSOWriter` leaves it so
+        // until the image is finished.
+        if index[index.len() - 1] == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Compressed image has no index, it was not finished",
+            ));
+        }
+        let block = vec![0u8; header.block_size as usize];
+        Ok(Self {
+            inner,
+            header,
+            index,
+            block,
+            compressed: Vec::new(),
+            cached: None,
+            pos: 0,
+        })
+    }
+
+    fn block_len(&self, block: usize) -> usize {
+        let start = block as u64 * self.header.block_size as u64;
+        (self.header.total_bytes - start).min(self.header.block_size as u64) as usize
+    }
+
+    fn load_block(&mut self, block: usize) -> std::io::Result<()> {
+        if self.cached == Some(block) {
+            return Ok(());
+        }
+        let entry = self.index[block];
+        let start = ((entry & !INDEX_FLAG) as u64) << self.header.align;
+        let end = ((self.index[block + 1] & !INDEX_FLAG) as u64) << self.header.align;
+        if end < start {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Corrupt index entry for block {}", block),
+            ));
+        }
+        let len = self.block_len(block);
+        let stored = (end - start) as usize;
+        self.compressed.resize(stored, 0);
+        self.inner.seek(SeekFrom::Start(start))?;
+        self.inner.read_exact(&mut self.compressed)?;
+
+        let out = &mut self.block[0..len];
+        let flag = (entry & INDEX_FLAG) != 0;
+        let plain = match (self.header.format, self.header.version) {
+            (CSOFormat::CSO, 2) => stored >= self.header.block_size as usize,
+            _ => flag,
+        };
+        if plain {
+            let src = self.compressed.get(0..len).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Stored block {} is too short", block),
+                )
+            })?;
+            out.copy_from_slice(src);
+        } else if self.header.format == CSOFormat::ZSO || flag {
+            lz4_decompress(&self.compressed, out)?;
+        } else {
+            DeflateDecoder::new(&self.compressed[..]).read_exact(out)?;
+        }
+        self.cached = Some(block);
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for CSOReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.header.total_bytes || buf.is_empty() {
+            return Ok(0);
+        }
+        let block = (self.pos / self.header.block_size as u64) as usize;
+        let off = (self.pos % self.header.block_size as u64) as usize;
+        self.load_block(block)?;
+        let len = (self.block_len(block) - off).min(buf.len());
+        buf[0..len].copy_from_slice(&self.block[off..off + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for CSOReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(off) => off as i64,
+            SeekFrom::End(off) => self.header.total_bytes as i64 + off,
+            SeekFrom::Current(off) => self.pos as i64 + off,
+        };
+        if pos < 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before start of image",
+            ));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+fn truncated() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "Truncated LZ4 block")
+}
+
+/// Decodes a raw LZ4 block into `output`, stopping once it is full. Stored
+/// blocks are padded out to the index alignment, so trailing input is ignored.
+fn lz4_decompress(input: &[u8], output: &mut [u8]) -> std::io::Result<()> {
+    let mut ip = 0;
+    let mut op = 0;
+    macro_rules! read_len {
+        ($len:expr) => {{
+            let mut len = $len;
+            if len == 15 {
+                loop {
+                    let b = *input.get(ip).ok_or_else(truncated)?;
+                    ip += 1;
+                    len += b as usize;
+                    if b != 255 {
+                        break;
+                    }
+                }
+            }
+            len
+        }};
+    }
+    while op < output.len() {
+        let token = *input.get(ip).ok_or_else(truncated)?;
+        ip += 1;
+        let lit = read_len!((token >> 4) as usize);
+        let lit = lit.min(output.len() - op);
+        let src = input.get(ip..ip + lit).ok_or_else(truncated)?;
+        output[op..op + lit].copy_from_slice(src);
+        ip += lit;
+        op += lit;
+        if op >= output.len() {
+            break;
+        }
+        let off = input.get(ip..ip + 2).ok_or_else(truncated)?;
+        let off = u16::from_le_bytes([off[0], off[1]]) as usize;
+        ip += 2;
+        if off == 0 || off > op {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "LZ4 match offset outside of the block",
+            ));
+        }
+        let len = read_len!((token & 0xf) as usize) + 4;
+        for _ in 0..len.min(output.len() - op) {
+            output[op] = output[op - off];
+            op += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Compresses a sequentially written image into a CISO v1 or ZISO container.
+/// The total size has to be known up front: `new` writes the header and a
+/// zeroed placeholder index, and blocks follow it as they are compressed.
+/// Index entries are kept in memory (4 bytes per block) until `finish` writes
+/// them over the placeholder, so an image that was never finished still has
+/// the zeroed index, which `CSOReader` rejects.
+pub struct CSOWriter<W: Write + Seek> {
+    inner: W,
+    options: CSOOptions,
+    align: u8,
+    index: Vec<u32>,
+    block: Vec<u8>,
+    expected: usize,
+    pos: u64,
+}
+
+impl<W: Write + Seek> CSOWriter<W> {
+    pub fn new(mut inner: W, options: &CSOOptions, total_bytes: u64) -> std::io::Result<Self> {
+        if options.block_size == 0 || !options.block_size.is_multiple_of(2048) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Block size {} is not a multiple of 2048",
+                    options.block_size
+                ),
+            ));
+        }
+        if options.level > 9 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Compression level {} is not between 0 and 9", options.level),
+            ));
+        }
+        let num_blocks = total_bytes.div_ceil(options.block_size as u64) as usize;
+        let index_start = 0x18u64;
+        let data_start = index_start + (num_blocks as u64 + 1) * 4;
+
+        // Offsets are stored shifted right by `align`, in 31 bits. Worst case
+        // every block is stored plain and padded to the alignment.
+        let mut align = 0u8;
+        while (data_start + total_bytes + ((num_blocks as u64) << align)) >> align >= 1 << 31 {
+            align += 1;
+        }
+
+        inner.write_all(match options.format {
+            CSOFormat::CSO => b"CISO",
+            CSOFormat::ZSO => b"ZISO",
+        })?;
+        inner.write_u32::<LittleEndian>(0x18)?;
+        inner.write_u64::<LittleEndian>(total_bytes)?;
+        inner.write_u32::<LittleEndian>(options.block_size)?;
+        inner.write_u8(1)?;
+        inner.write_u8(align)?;
+        inner.write_u16::<LittleEndian>(0)?;
+        inner.write_all(&vec![0u8; (num_blocks + 1) * 4])?;
+
+        Ok(Self {
+            inner,
+            options: options.clone(),
+            align,
+            index: Vec::with_capacity(num_blocks + 1),
+            block: Vec::with_capacity(options.block_size as usize),
+            expected: num_blocks,
+            pos: data_start,
+        })
+    }
+
+    fn pad_to_align(&mut self) -> std::io::Result<()> {
+        let aligned = ((self.pos + (1 << self.align) - 1) >> self.align) << self.align;
+        self.inner
+            .write_all(&vec![0u8; (aligned - self.pos) as usize])?;
+        self.pos = aligned;
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> std::io::Result<()> {
+        self.pad_to_align()?;
+        let compressed = match self.options.format {
+            CSOFormat::CSO => {
+                let mut enc = DeflateEncoder::new(Vec::new(), Compression::new(self.options.level));
+                enc.write_all(&self.block)?;
+                enc.finish()?
+            }
+            CSOFormat::ZSO => lz4_flex::block::compress(&self.block),
+        };
+        let entry = (self.pos >> self.align) as u32;
+        if compressed.len() < self.block.len() {
+            self.index.push(entry);
+            self.inner.write_all(&compressed)?;
+            self.pos += compressed.len() as u64;
+        } else {
+            self.index.push(entry | INDEX_FLAG);
+            self.inner.write_all(&self.block)?;
+            self.pos += self.block.len() as u64;
+        }
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes out the last partial block and the index, returning the inner writer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        if !self.block.is_empty() {
+            self.flush_block()?;
+        }
+        if self.index.len() != self.expected {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Wrote {} blocks but the header announced {}",
+                    self.index.len(),
+                    self.expected
+                ),
+            ));
+        }
+        self.pad_to_align()?;
+        self.index.push((self.pos >> self.align) as u32);
+        self.inner.seek(SeekFrom::Start(0x18))?;
+        for entry in self.index.iter() {
+            self.inner.write_u32::<LittleEndian>(*entry)?;
+        }
+        self.inner.seek(SeekFrom::Start(self.pos))?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write + Seek> Write for CSOWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = (self.options.block_size as usize - self.block.len()).min(buf.len());
+        self.block.extend_from_slice(&buf[0..len]);
+        if self.block.len() == self.options.block_size as usize {
+            self.flush_block()?;
+        }
+        Ok(len)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Text, zeros and noise, ending in a partial block.
+    fn sample(len: usize) -> Vec<u8> {
+        let mut seed = 1u32;
+        (0..len)
+            .map(|i| match (i / 5000) % 3 {
+                0 => b"compressible "[i % 13],
+                1 => 0,
+                _ => {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    (seed >> 16) as u8
+                }
+            })
+            .collect()
+    }
+
+    fn compress(data: &[u8], options: &CSOOptions) -> std::io::Result<Vec<u8>> {
+        let mut out = CSOWriter::new(Cursor::new(Vec::new()), options, data.len() as u64)?;
+        out.write_all(data)?;
+        Ok(out.finish()?.into_inner())
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = sample(40000);
+        for (format, block_size, level) in [
+            (CSOFormat::CSO, 2048, 9),
+            (CSOFormat::CSO, 8192, 0),
+            (CSOFormat::ZSO, 2048, 9),
+        ] {
+            let options = CSOOptions {
+                format,
+                block_size,
+                level,
+            };
+            let image = compress(&data, &options).unwrap();
+            let mut read = CSOReader::new(Cursor::new(&image)).unwrap();
+            assert_eq!(read.header.format, format);
+            assert_eq!(read.header.total_bytes, data.len() as u64);
+            let mut decoded = Vec::new();
+            read.read_to_end(&mut decoded).unwrap();
+            assert!(decoded == data, "{:?} {}", format, block_size);
+
+            let mut buff = [0u8; 3000];
+            read.seek(SeekFrom::Start(14000)).unwrap();
+            read.read_exact(&mut buff).unwrap();
+            assert_eq!(&buff[..], &data[14000..17000]);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_options() {
+        for (block_size, level) in [(1000, 9), (0, 9), (2048, 10)] {
+            let options = CSOOptions {
+                block_size,
+                level,
+                ..Default::default()
+            };
+            let e = compress(&[0; 4096], &options).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn rejects_unfinished_image() {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = CSOWriter::new(&mut out, &CSOOptions::default(), 8192).unwrap();
+        writer.write_all(&[0; 4096]).unwrap();
+        drop(writer);
+        let e = CSOReader::new(Cursor::new(out.into_inner())).err().unwrap();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,232 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+
+/// Bytes at the start of a file that CRILAYLA stores as is, after the payload.
+const RAW_PREFIX: usize = 0x100;
+/// Back references cover 3 to 3 + 0x1fff bytes.
+const MIN_DISTANCE: usize = 3;
+const MAX_DISTANCE: usize = MIN_DISTANCE + 0x1fff;
+const MIN_LENGTH: usize = 3;
+
+const HASH_BITS: usize = 15;
+const WINDOW: usize = 0x4000;
+/// Candidates checked per position before taking the best match so far.
+const MAX_CHAIN: usize = 128;
+/// A match this long is taken without looking any further.
+const NICE_LENGTH: usize = 512;
+const NONE: u32 = u32::MAX;
+
+/// Bits packed most significant first, which the decoder reads back from the
+/// end of the payload.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    curr: u32,
+    used: usize,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: usize, count: usize) {
+        for i in (0..count).rev() {
+            self.curr = (self.curr << 1) | ((value >> i) & 1) as u32;
+            self.used += 1;
+            if self.used == 8 {
+                self.bytes.push(self.curr as u8);
+                self.curr = 0;
+                self.used = 0;
+            }
+        }
+    }
+    fn finish(mut self) -> Vec<u8> {
+        if self.used > 0 {
+            self.bytes.push((self.curr << (8 - self.used)) as u8);
+        }
+        self.bytes
+    }
+}
+
+fn hash(data: &[u8]) -> usize {
+    let key = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+    (key.wrapping_mul(0x9e3779b1) >> (32 - HASH_BITS)) as usize
+}
+
+/// Hash chains over the last `WINDOW` positions.
+struct Matcher {
+    head: Vec<u32>,
+    prev: Vec<u32>,
+}
+
+impl Matcher {
+    fn new() -> Self {
+        Self {
+            head: vec![NONE; 1 << HASH_BITS],
+            prev: vec![NONE; WINDOW],
+        }
+    }
+    fn insert(&mut self, data: &[u8], pos: usize) {
+        if pos + MIN_LENGTH <= data.len() {
+            let key = hash(&data[pos..]);
+            self.prev[pos % WINDOW] = self.head[key];
+            self.head[key] = pos as u32;
+        }
+    }
+    /// Longest earlier match for `pos` as (length, distance).
+    fn find(&self, data: &[u8], pos: usize) -> (usize, usize) {
+        let mut best = (0, 0);
+        if pos + MIN_LENGTH > data.len() {
+            return best;
+        }
+        let mut cand = self.head[hash(&data[pos..])];
+        for _ in 0..MAX_CHAIN {
+            if cand == NONE || pos - cand as usize > MAX_DISTANCE {
+                break;
+            }
+            let start = cand as usize;
+            if pos - start >= MIN_DISTANCE {
+                let len = data[pos..]
+                    .iter()
+                    .zip(data[start..].iter())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best.0 {
+                    best = (len, pos - start);
+                    if len >= NICE_LENGTH {
+                        break;
+                    }
+                }
+            }
+            cand = self.prev[start % WINDOW];
+        }
+        best
+    }
+}
+
+/// Lengths past the minimum, in fields of 2, 3, 5 and 8 bits that each
+/// continue into the next when full, then in 8-bit fields until one is not.
+fn write_length(bits: &mut BitWriter, len: usize) {
+    let mut rem = len - MIN_LENGTH;
+    for width in [2, 3, 5, 8] {
+        let max = (1 << width) - 1;
+        if rem < max {
+            bits.write(rem, width);
+            return;
+        }
+        bits.write(max, width);
+        rem -= max;
+    }
+    while rem >= 0xff {
+        bits.write(0xff, 8);
+        rem -= 0xff;
+    }
+    bits.write(rem, 8);
+}
+
+/// The inverse of `crilayla_decompress_into`. The data after the first 0x100
+/// bytes is compressed back to front, and those bytes are appended raw.
+/// Returns `None` if the data is too short for the format or does not get
+/// any smaller.
+pub fn crilayla_compress(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() <= RAW_PREFIX {
+        return None;
+    }
+    let (prefix, body) = data.split_at(RAW_PREFIX);
+    let src = body.iter().rev().copied().collect::<Vec<_>>();
+
+    let mut bits = BitWriter::default();
+    let mut matcher = Matcher::new();
+    let mut pos = 0;
+    while pos < src.len() {
+        let (len, distance) = matcher.find(&src, pos);
+        if len >= MIN_LENGTH {
+            bits.write(1, 1);
+            bits.write(distance - MIN_DISTANCE, 13);
+            write_length(&mut bits, len);
+            for i in pos..pos + len {
+                matcher.insert(&src, i);
+            }
+            pos += len;
+        } else {
+            bits.write(0, 1);
+            bits.write(src[pos] as usize, 8);
+            matcher.insert(&src, pos);
+            pos += 1;
+        }
+    }
+    let mut payload = bits.finish();
+    payload.reverse();
+
+    let mut out = Vec::with_capacity(0x10 + payload.len() + RAW_PREFIX);
+    out.extend_from_slice(b"CRILAYLA");
+    out.write_u32::<LittleEndian>(body.len() as u32).ok()?;
+    out.write_u32::<LittleEndian>(payload.len() as u32).ok()?;
+    out.extend_from_slice(&payload);
+    out.extend_from_slice(prefix);
+    if out.len() >= data.len() {
+        return None;
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::cpk::decompress::{crilayla_decompress_into, CrilaylaError};
+
+    fn round_trip(data: &[u8]) {
+        let packed = crilayla_compress(data).expect("data should compress");
+        assert!(packed.len() < data.len());
+        let mut out = Vec::new();
+        crilayla_decompress_into(&packed, &mut out).unwrap();
+        assert!(out == data);
+    }
+
+    /// Bytes from a xorshift generator, which no back reference can shorten.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut x = 0x2545f491u32;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn short_data_is_not_compressed() {
+        assert!(crilayla_compress(&[]).is_none());
+        assert!(crilayla_compress(&[0; RAW_PREFIX]).is_none());
+    }
+
+    #[test]
+    fn incompressible_data_is_not_compressed() {
+        assert!(crilayla_compress(&noise(0x4000)).is_none());
+    }
+
+    #[test]
+    fn text_round_trips() {
+        let text = "Persona 2: Eternal Punishment. ".repeat(200);
+        round_trip(text.as_bytes());
+    }
+
+    #[test]
+    fn long_runs_round_trip() {
+        // 3 + 3 + 7 + 31 + 255 is where lengths continue in 8-bit fields.
+        for run in [297, 298, 299, 300, 298 + 255, 299 + 255, 5000, 70000] {
+            let mut data = noise(RAW_PREFIX + 16);
+            data.resize(data.len() + run, 0xaa);
+            data.extend(noise(32));
+            round_trip(&data);
+        }
+    }
+
+    #[test]
+    fn oversized_header_is_rejected() {
+        let text = "Persona 2: Eternal Punishment. ".repeat(200);
+        let mut packed = crilayla_compress(text.as_bytes()).unwrap();
+        packed[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut out = Vec::new();
+        let result = crilayla_decompress_into(&packed, &mut out);
+        assert_eq!(result, Err(CrilaylaError::TooLarge));
+    }
+}
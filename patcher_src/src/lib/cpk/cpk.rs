@@ -1,19 +1,21 @@
+use std::io::{Cursor, Read, Seek, Write};
 use std::ops::ControlFlow;
-use std::path::PathBuf;
 use std::{collections::HashMap, io::SeekFrom};
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
-use crate::lib::util::{
-    read_bytes_at, read_string_n, write_bytes_at, write_string, write_string_at, BinaryStruct,
-};
+use crate::lib::overlay::{ReadSeek, Stream};
+use crate::lib::util::{read_bytes_at, read_string_n, write_string, write_string_at, BinaryStruct};
 
-use super::decompress::crilayla_decompress;
+use super::compress::crilayla_compress;
+use super::decompress::crilayla_decompress_into;
 use super::utf::{UTFDataType, UTFStorage, UTFValue, UTF};
 
 #[derive(Debug)]
 pub struct CPK {
     utfs: HashMap<String, Box<UTF>>,
+    /// Stored bytes and extracted size of members given new contents.
+    replaced: HashMap<u32, (Vec<u8>, u32)>,
 }
 
 #[derive(Debug)]
@@ -23,23 +25,210 @@ pub struct CPKFile {
     pub file_size: u32,
     pub extract_size: u32,
     pub offset: u32,
+    /// CRC-32 of the stored (possibly compressed) data, if the TOC has one.
+    pub crc: Option<u32>,
 }
 
+/// A CRC in an archive that does not match the data it covers.
+#[derive(Debug, Clone)]
+pub struct CrcMismatch {
+    /// The table, or the member's ID and name.
+    pub what: String,
+    pub stored: u32,
+    pub computed: u32,
+}
+
+impl std::fmt::Display for CrcMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: stored CRC {:08x}, computed {:08x}",
+            self.what, self.stored, self.computed
+        )
+    }
+}
+
+/// Tables after the header, with the prefix of their `Offset`, `Size` and
+/// `Crc` header columns.
+const TABLES: [(&str, &str); 4] = [
+    ("TOC", "Toc"),
+    ("ITOC", "Itoc"),
+    ("GTOC", "Gtoc"),
+    ("ETOC", "Etoc"),
+];
+
+/// Header columns describing the content, which every archive has.
+const CONTENT_FIELDS: [&str; 4] = [
+    "ContentOffset",
+    "ContentSize",
+    "EnabledPackedSize",
+    "EnabledDataSize",
+];
+
 fn read_utfpacket<R: std::io::Read + std::io::Seek>(
     read: &mut R,
     expected: &str,
 ) -> std::io::Result<(u64, Box<UTF>)> {
     let name = read_string_n(read, 4)?;
     if name != expected {
-        panic!("unexpected UTF packet {}", name);
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Expected a {:?} packet, found {:?}", expected, name),
+        ));
     }
     let unk = read.read_u32::<LittleEndian>()?;
-    assert_eq!(unk, 255);
+    if unk != 0xff {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Unexpected {} packet flags {:#x}", expected.trim(), unk),
+        ));
+    }
     let size = read.read_u64::<LittleEndian>()?;
     let utf = UTF::read(read)?;
     Ok((size, utf))
 }
 
+/// Writes a table from `UTF::encode`.
+fn write_utfpacket<W: std::io::Write + std::io::Seek>(
+    write: &mut W,
+    name: &str,
+    offset: usize,
+    data: &[u8],
+) -> std::io::Result<()> {
+    write.seek(SeekFrom::Start(offset as u64))?;
+    write_string(write, &format!("{:<4}", name))?;
+    write.write_u32::<LittleEndian>(0xff)?;
+    write.write_u64::<LittleEndian>(data.len() as u64)?;
+    write.write_all(data)
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+/// A value of the header's only row, if it is stored and not zero.
+fn header_u32(header: &UTF, name: &str) -> Option<u32> {
+    let value = header.rows.first()?.get(name)?.as_ref()?.as_u32()?;
+    Some(value).filter(|x| *x != 0)
+}
+
+/// The value of an integer column, which the row must have.
+fn value_u32(utf: &UTF, row: usize, name: &str) -> std::io::Result<u32> {
+    let value = utf.rows.get(row).and_then(|x| x.get(name)?.as_ref());
+    value.and_then(|x| x.as_u32()).ok_or_else(|| {
+        invalid(format!(
+            "CPK {} row {} has no integer {}",
+            utf.name, row, name
+        ))
+    })
+}
+
+/// Stores `value` per row at the column's own width. A constant or zero
+/// column changes the table's size, so do it before `calculate_size`.
+fn set_value(utf: &mut UTF, row: usize, name: &str, value: u64) -> std::io::Result<()> {
+    if !utf.col_lookup.contains_key(name) || row >= utf.rows.len() {
+        return Err(invalid(format!(
+            "CPK {} has no {} column or row {}",
+            utf.name, name, row
+        )));
+    }
+    let col = utf.get_col_mut(name);
+    col.storage = UTFStorage::PER_ROW;
+    let value = int_value(col.dtype, value);
+    utf.rows[row].insert(name.into(), Some(value));
+    Ok(())
+}
+
+fn int_value(dtype: UTFDataType, value: u64) -> UTFValue {
+    match dtype {
+        UTFDataType::U8 | UTFDataType::U8_2 => UTFValue::U8(value as u8),
+        UTFDataType::U16 | UTFDataType::U16_2 => UTFValue::U16(value as u16),
+        UTFDataType::U32 | UTFDataType::U32_2 => UTFValue::U32(value as u32),
+        _ => UTFValue::U64(value),
+    }
+}
+
+/// An ITOC `DataL`/`DataH` table of (ID, FileSize, ExtractSize) rows.
+fn itoc_sizes(name: &str, dtype: UTFDataType, rows: &[(u32, u32, u32)]) -> UTF {
+    let mut utf = UTF::new(name.into());
+    for col in ["ID", "FileSize", "ExtractSize"] {
+        let dtype = if col == "ID" { UTFDataType::U16 } else { dtype };
+        utf.add_col(col.into(), dtype, UTFStorage::PER_ROW, None);
+    }
+    for &(id, file_size, extract_size) in rows {
+        utf.rows.push(HashMap::from([
+            ("ID".into(), Some(UTFValue::U16(id as u16))),
+            ("FileSize".into(), Some(int_value(dtype, file_size as u64))),
+            (
+                "ExtractSize".into(),
+                Some(int_value(dtype, extract_size as u64)),
+            ),
+        ]));
+    }
+    utf
+}
+
+/// Where a member's stored bytes come from when the archive is written.
+#[derive(Debug)]
+enum Stored {
+    /// Copied from the source archive, at this offset and size.
+    Source(u32, u32),
+    Memory(Vec<u8>),
+}
+
+/// A rebuilt archive. Unchanged members stay in the source image until it is
+/// written, so the archive never has to be held in memory or on disk.
+#[derive(Debug)]
+pub struct CPKImage {
+    /// Offset of the source archive in the image given to `write_to`.
+    base: u64,
+    /// The header, TOC, ITOC and GTOC packets.
+    head: Vec<u8>,
+    members: Vec<(u64, Stored)>,
+    /// The ETOC packet, after the content.
+    tail: Option<(u64, Vec<u8>)>,
+    size: u64,
+}
+
+impl Stream for CPKImage {
+    fn size(&self) -> u32 {
+        self.size as u32
+    }
+
+    fn write_to(&self, write: &mut dyn Write, image: &mut dyn ReadSeek) -> std::io::Result<()> {
+        let mut pos = 0;
+        let pad = |write: &mut dyn Write, pos: &mut u64, to: u64| {
+            let res = std::io::copy(&mut std::io::repeat(0).take(to - *pos), write);
+            *pos = to;
+            res
+        };
+        write.write_all(&self.head)?;
+        pos += self.head.len() as u64;
+        let mut buff = Vec::new();
+        for (offset, stored) in self.members.iter() {
+            pad(write, &mut pos, *offset)?;
+            let data = match stored {
+                Stored::Source(src, size) => {
+                    buff.resize(*size as usize, 0);
+                    image.seek(SeekFrom::Start(self.base + *src as u64))?;
+                    image.read_exact(&mut buff)?;
+                    &buff
+                }
+                Stored::Memory(data) => data,
+            };
+            write.write_all(data)?;
+            pos += data.len() as u64;
+        }
+        if let Some((offset, data)) = &self.tail {
+            pad(write, &mut pos, *offset)?;
+            write.write_all(data)?;
+            pos += data.len() as u64;
+        }
+        pad(write, &mut pos, self.size)?;
+        Ok(())
+    }
+}
+
 // struct CPKWriteFile {
 //     pub id: u32,
 //     pub name: String,
@@ -47,31 +236,199 @@ fn read_utfpacket<R: std::io::Read + std::io::Seek>(
 //     pub offset: u32
 // }
 impl CPK {
-    pub fn write_cpk<W: std::io::Write + std::io::Seek>(
-        &mut self,
-        dir: PathBuf,
-        write: &mut W,
-    ) -> std::io::Result<()> {
-        let files: Vec<std::fs::DirEntry> = dir
-            .read_dir()?
-            .collect::<std::io::Result<Vec<std::fs::DirEntry>>>()?;
-        let mut file_map = HashMap::new();
-        let mut content_size = 0;
-        let mut padded_size = 0;
-        files.iter().try_for_each(|file| {
-            let name = String::from(file.file_name().to_str().unwrap());
-            let end = name.find('.').unwrap();
-            let id = name[0..end].parse::<u32>().unwrap();
-            let size = file.metadata()?.len() as u32;
-            content_size += size;
-            padded_size += (size + 0x7ff) & !0x7ff;
-            file_map.insert(id, size);
-            Ok(()) as std::io::Result<()>
-        })?;
+    /// GTOC groups members, and is written as it was read, so members can
+    /// only be added or removed in archives without one.
+    fn check_no_groups(&self, what: &str) -> std::io::Result<()> {
+        if self.utfs.contains_key("GTOC") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("Can't {} a CPK member, the archive has a GTOC", what),
+            ));
+        }
+        Ok(())
+    }
 
+    /// Adds a member with `data` at the end of the TOC.
+    pub fn add_file(&mut self, id: u32, name: &str, data: Vec<u8>) -> std::io::Result<()> {
+        self.check_no_groups("add")?;
+        if self.files()?.iter().any(|x| x.id == id) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("CPK member {} already exists", id),
+            ));
+        }
+        let toc = self.utfs.get_mut("TOC").unwrap();
+        let index = toc.rows.len();
+        let mut row = toc.default_row();
+        if let Some(Some(_)) = row.get("FileName") {
+            row.insert("FileName".into(), Some(UTFValue::STRING(name.into())));
+        }
+        toc.rows.push(row);
+        set_value(toc, index, "ID", id as u64)?;
+        for field in ["FileSize", "ExtractSize", "FileOffset"] {
+            set_value(toc, index, field, 0)?;
+        }
+
+        // ETOC has a row per member, plus one after them. A new member has
+        // no update time.
+        if let Some(etoc) = self.utfs.get_mut("ETOC") {
+            let row = etoc.default_row();
+            etoc.rows.insert(index.min(etoc.rows.len()), row);
+        }
+        self.replace_file(id, data)
+    }
+
+    /// Gives a member new contents, which are written by `layout` in place
+    /// of the source's. Members the source stored compressed are compressed
+    /// again; the rest are stored as they are.
+    pub fn replace_file(&mut self, id: u32, data: Vec<u8>) -> std::io::Result<()> {
+        let Some(file) = self.files()?.into_iter().find(|x| x.id == id) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("CPK member {} does not exist", id),
+            ));
+        };
+        let packed = if file.file_size != file.extract_size {
+            crilayla_compress(&data)
+        } else {
+            None
+        };
+        let extract_size = data.len() as u32;
+        self.replaced
+            .insert(id, (packed.unwrap_or(data), extract_size));
+        Ok(())
+    }
+
+    /// Drops a member from the TOC and ETOC.
+    pub fn remove_file(&mut self, id: u32) -> std::io::Result<()> {
+        self.check_no_groups("remove")?;
+        let Some(index) = self.files()?.iter().position(|x| x.id == id) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("CPK member {} does not exist", id),
+            ));
+        };
+        self.utfs.get_mut("TOC").unwrap().rows.remove(index);
+        if let Some(etoc) = self.utfs.get_mut("ETOC") {
+            if index < etoc.rows.len() {
+                etoc.rows.remove(index);
+            }
+        }
+        self.replaced.remove(&id);
+        Ok(())
+    }
+
+    /// Regenerates ITOC from the TOC, in ID order. It either maps IDs to TOC
+    /// rows (`TocIndex`) or lists their sizes, in `DataL` if both fit in 16
+    /// bits and `DataH` otherwise.
+    fn rebuild_itoc(&mut self) -> std::io::Result<()> {
+        let toc = &self.utfs["TOC"];
+        let mut members = (0..toc.rows.len())
+            .map(|i| {
+                let value = |x: &str| value_u32(toc, i, x);
+                Ok((value("ID")?, i, value("FileSize")?, value("ExtractSize")?))
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+        members.sort_by_key(|x| x.0);
+
+        let itoc = self.utfs.get_mut("ITOC").unwrap();
+        if itoc.col_lookup.contains_key("TocIndex") {
+            let template = itoc.default_row();
+            itoc.rows = vec![template; members.len()];
+            for (row, (id, index, _, _)) in members.into_iter().enumerate() {
+                set_value(itoc, row, "ID", id as u64)?;
+                set_value(itoc, row, "TocIndex", index as u64)?;
+            }
+        } else if itoc.col_lookup.contains_key("DataL") {
+            if let Some((id, ..)) = members.iter().find(|x| x.0 > u16::MAX as u32) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("CPK member {} does not fit in a 16-bit ITOC ID", id),
+                ));
+            }
+            let (low, high): (Vec<_>, Vec<_>) = members
+                .into_iter()
+                .map(|(id, _, file_size, extract_size)| (id, file_size, extract_size))
+                .partition(|x| x.1 <= u16::MAX as u32 && x.2 <= u16::MAX as u32);
+            let mut data = Vec::new();
+            for (name, dtype, rows) in [
+                ("CpkItocL", UTFDataType::U16, &low),
+                ("CpkItocH", UTFDataType::U32, &high),
+            ] {
+                data.push(itoc_sizes(name, dtype, rows).encode()?);
+            }
+            if itoc.rows.is_empty() {
+                itoc.rows.push(itoc.default_row());
+            }
+            for (column, count) in [("FilesL", low.len()), ("FilesH", high.len())] {
+                if itoc.col_lookup.contains_key(column) {
+                    set_value(itoc, 0, column, count as u64)?;
+                }
+            }
+            for (column, data) in ["DataL", "DataH"].into_iter().zip(data) {
+                if !itoc.col_lookup.contains_key(column) {
+                    return Err(invalid(format!("CPK ITOC has DataL but no {}", column)));
+                }
+                itoc.get_col_mut(column).storage = UTFStorage::PER_ROW;
+                itoc.rows[0].insert(column.into(), Some(UTFValue::BYTES(data)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Lays out the archive with the current members, for writing with
+    /// `CPKImage::write_to`. Members without new contents are copied from
+    /// the source archive, which is read through `read` here and found at
+    /// `base` in the image given to `write_to`.
+    pub fn layout<R: std::io::Read + std::io::Seek>(
+        mut self,
+        read: &mut R,
+        base: u64,
+    ) -> std::io::Result<CPKImage> {
+        let files = self.files()?;
+        let file_crc = self.utfs["TOC"].col_lookup.contains_key("CRC");
+        let mut stored = Vec::new();
+        let mut packed_size = 0u64;
+        let mut data_size = 0u64;
+        {
+            let toc = self.utfs.get_mut("TOC").unwrap();
+            for (i, file) in files.into_iter().enumerate() {
+                let (data, extract_size) = match self.replaced.remove(&file.id) {
+                    Some((packed, extract_size)) => (Stored::Memory(packed), extract_size),
+                    None => (
+                        Stored::Source(file.offset, file.file_size),
+                        file.extract_size,
+                    ),
+                };
+                let file_size = match &data {
+                    Stored::Source(_, size) => *size,
+                    Stored::Memory(packed) => packed.len() as u32,
+                };
+                if file_crc {
+                    let crc = match (&data, file.crc) {
+                        (Stored::Source(..), Some(crc)) => crc,
+                        (Stored::Source(offset, size), None) => {
+                            crc32fast::hash(&read_bytes_at(read, *offset, *size)?)
+                        }
+                        (Stored::Memory(packed), _) => crc32fast::hash(packed),
+                    };
+                    set_value(toc, i, "CRC", crc as u64)?;
+                }
+                set_value(toc, i, "FileSize", file_size as u64)?;
+                set_value(toc, i, "ExtractSize", extract_size as u64)?;
+                // Room for the offset, which is only known once the tables
+                // are sized.
+                set_value(toc, i, "FileOffset", 0)?;
+                packed_size += file_size as u64;
+                data_size += extract_size as u64;
+                stored.push((file_size, data));
+            }
+        }
+        self.rebuild_itoc()?;
+
+        let present = TABLES.map(|(table, _)| self.utfs.contains_key(table));
         {
             let cpk_header = self.utfs.get_mut("CpkHeader").unwrap();
-            cpk_header.get_col_mut("Groups").storage = UTFStorage::ZERO;
 
             // "ContentOffset": 16384,
             // "ContentSize": 264429568,
@@ -89,165 +446,234 @@ impl CPK {
             // "EID": 1,
             // "CpkMode": 5,
 
-            cpk_header.remove_column("EtocOffset");
-            cpk_header.remove_column("EtocSize");
-            cpk_header.remove_column("TocCrc");
-            cpk_header.remove_column("ItocCrc");
-            cpk_header.remove_column("GtocOffset");
-            cpk_header.remove_column("GtocSize");
-            cpk_header.remove_column("GtocCrc");
-            cpk_header.add_col(
-                "EnableTocCrc".into(),
-                UTFDataType::U16,
-                UTFStorage::PER_ROW,
-                Some(UTFValue::U16(0)),
-            );
-            cpk_header.add_col(
-                "EnableFileCrc".into(),
-                UTFDataType::U16,
-                UTFStorage::PER_ROW,
-                Some(UTFValue::U16(0)),
-            );
+            if cpk_header.col_lookup.contains_key("Files") {
+                set_value(cpk_header, 0, "Files", stored.len() as u64)?;
+            }
+            // Locations, sizes and CRCs are only known once everything else
+            // is; make room for them first so the sizes computed below stay
+            // right.
+            for field in CONTENT_FIELDS {
+                set_value(cpk_header, 0, field, 0)?;
+            }
+            for ((_, prefix), present) in TABLES.into_iter().zip(present) {
+                for field in ["Offset", "Size", "Crc"] {
+                    let column = format!("{}{}", prefix, field);
+                    if present && cpk_header.col_lookup.contains_key(&column) {
+                        set_value(cpk_header, 0, &column, 0)?;
+                    }
+                }
+            }
         }
 
-        let header_len = self.utfs.get_mut("CpkHeader").unwrap().calculate_size();
-        let toc_len = self.utfs.get_mut("TOC").unwrap().calculate_size();
-        let itoc_len = self.utfs.get_mut("ITOC").unwrap().calculate_size();
+        let header_len = self.utfs.get_mut("CpkHeader").unwrap().calculate_size()?;
+        let toc_len = self.utfs.get_mut("TOC").unwrap().calculate_size()?;
+        let itoc_len = self.utfs.get_mut("ITOC").unwrap().calculate_size()?;
+        let gtoc_len = self
+            .utfs
+            .get_mut("GTOC")
+            .map(|x| x.calculate_size())
+            .transpose()?;
+        let etoc_len = self
+            .utfs
+            .get_mut("ETOC")
+            .map(|x| x.calculate_size())
+            .transpose()?;
 
-        let content_offset =
-            align!(header_len, 2048) + align!(toc_len, 2048) + align!(itoc_len, 2048);
+        // Header, TOC, ITOC and GTOC, then the content, then ETOC.
+        let toc_offset = align!(header_len, 2048);
+        let itoc_offset = toc_offset + align!(toc_len, 2048);
+        let gtoc_offset = itoc_offset + align!(itoc_len, 2048);
+        let content_offset = gtoc_offset + gtoc_len.map_or(0, |x| align!(x, 2048));
         let mut current_off = content_offset;
-
-        self.utfs
-            .get_mut("TOC")
-            .unwrap()
-            .rows
-            .iter_mut()
-            .for_each(|x| {
-                let id: u32 = x["ID"].as_ref().unwrap().into();
-                x.insert(String::from("FileSize"), Some(UTFValue::U32(file_map[&id])));
-                x.insert(
-                    String::from("ExtractSize"),
-                    Some(UTFValue::U32(file_map[&id])),
-                );
-                x.insert(
-                    String::from("FileOffset"),
-                    Some(UTFValue::U64((current_off - 0x800) as u64)),
-                );
-                current_off += align!(file_map[&id] as usize, 2048);
-            });
+        let mut members = Vec::new();
+        {
+            let toc = self.utfs.get_mut("TOC").unwrap();
+            for (i, (file_size, data)) in stored.into_iter().enumerate() {
+                set_value(toc, i, "FileOffset", (current_off - toc_offset) as u64)?;
+                members.push((current_off as u64, data));
+                current_off += align!(file_size as usize, 2048);
+            }
+        }
+        let padded_size = current_off - content_offset;
+        let etoc_offset = current_off;
         {
             let cpk_header = self.utfs.get_mut("CpkHeader").unwrap();
-            *cpk_header.rows[0].get_mut("ContentOffset").unwrap() =
-                Some(UTFValue::U64(content_offset as u64));
-            *cpk_header.rows[0].get_mut("ContentSize").unwrap() =
-                Some(UTFValue::U64(padded_size as u64));
-            *cpk_header.rows[0].get_mut("EnabledPackedSize").unwrap() =
-                Some(UTFValue::U64(content_size as u64));
-            *cpk_header.rows[0].get_mut("EnabledDataSize").unwrap() =
-                Some(UTFValue::U64(content_size as u64));
-            *cpk_header.rows[0].get_mut("TocSize").unwrap() = Some(UTFValue::U64(toc_len as u64));
-            *cpk_header.rows[0].get_mut("ItocSize").unwrap() = Some(UTFValue::U64(itoc_len as u64));
-            *cpk_header.rows[0].get_mut("TocOffset").unwrap() =
-                Some(UTFValue::U64(align!(header_len, 2048) as u64));
-            *cpk_header.rows[0].get_mut("ItocOffset").unwrap() = Some(UTFValue::U64(
-                align!(header_len, 2048) as u64 + align!(toc_len, 2048) as u64,
-            ));
-            *cpk_header.rows[0].get_mut("Groups").unwrap() = Some(UTFValue::U64(0));
+            for (field, value) in CONTENT_FIELDS.into_iter().zip([
+                content_offset as u64,
+                padded_size as u64,
+                packed_size,
+                data_size,
+            ]) {
+                set_value(cpk_header, 0, field, value)?;
+            }
+            set_value(cpk_header, 0, "TocOffset", toc_offset as u64)?;
+            set_value(cpk_header, 0, "TocSize", toc_len as u64)?;
+            set_value(cpk_header, 0, "ItocOffset", itoc_offset as u64)?;
+            set_value(cpk_header, 0, "ItocSize", itoc_len as u64)?;
+            if let Some(gtoc_len) = gtoc_len {
+                set_value(cpk_header, 0, "GtocOffset", gtoc_offset as u64)?;
+                set_value(cpk_header, 0, "GtocSize", gtoc_len as u64)?;
+            }
+            if let Some(etoc_len) = etoc_len {
+                set_value(cpk_header, 0, "EtocOffset", etoc_offset as u64)?;
+                set_value(cpk_header, 0, "EtocSize", etoc_len as u64)?;
+            }
         }
-        // *cpk_header.rows[0].get_mut("Groups").unwrap() = Some(UTFValue::U32(0));
-
-        write_string(write, "CPK ")?;
-        write.write_u32::<LittleEndian>(0xff)?;
-        write.write_u64::<LittleEndian>(header_len as u64)?;
-        self.utfs["CpkHeader"].write(write)?;
-        write.seek(SeekFrom::Start(2048 - 6))?;
-        write_string(write, "(c)CRI")?;
-
-        write_string(write, "TOC ")?;
-        write.write_u32::<LittleEndian>(0xff)?;
-        write.write_u64::<LittleEndian>(toc_len as u64)?;
-        self.utfs["TOC"].write(write)?;
-
-        write.seek(SeekFrom::Start(
-            align!(header_len, 2048) as u64 + align!(toc_len, 2048) as u64,
-        ))?;
-        write_string(write, "ITOC")?;
-        write.write_u32::<LittleEndian>(0xff)?;
-        write.write_u64::<LittleEndian>(itoc_len as u64)?;
-        self.utfs["ITOC"].write(write)?;
-
-        for row in self.utfs["TOC"].rows.iter() {
-            let mut path = dir.clone();
-            let id: u32 = row["ID"].as_ref().unwrap().into();
-            let off: u32 = row["FileOffset"].as_ref().unwrap().into();
-            path.push(format!("{}.bin", id));
-            let buff = std::fs::read(path)?;
-            write_bytes_at(write, (0x800 as u32) + off, &buff)?;
+
+        let mut tables = Vec::new();
+        for (offset, (table, prefix)) in [toc_offset, itoc_offset, gtoc_offset, etoc_offset]
+            .into_iter()
+            .zip(TABLES)
+        {
+            if let Some(utf) = self.utfs.get_mut(table) {
+                let data = utf.encode()?;
+                let cpk_header = self.utfs.get_mut("CpkHeader").unwrap();
+                let column = format!("{}Crc", prefix);
+                if cpk_header.col_lookup.contains_key(&column) {
+                    set_value(cpk_header, 0, &column, crc32fast::hash(&data) as u64)?;
+                }
+                tables.push((table, offset, data));
+            }
         }
 
-        // todo!()
-        Ok(())
+        let mut head = Cursor::new(Vec::new());
+        write_string(&mut head, "CPK ")?;
+        head.write_u32::<LittleEndian>(0xff)?;
+        head.write_u64::<LittleEndian>(header_len as u64)?;
+        self.utfs["CpkHeader"].write(&mut head)?;
+        head.seek(SeekFrom::Start(2048 - 6))?;
+        write_string(&mut head, "(c)CRI")?;
+
+        let mut tail = None;
+        for (table, offset, data) in tables {
+            if table == "ETOC" {
+                let mut packet = Cursor::new(Vec::new());
+                write_utfpacket(&mut packet, table, 0, &data)?;
+                tail = Some((offset as u64, packet.into_inner()));
+            } else {
+                write_utfpacket(&mut head, table, offset, &data)?;
+            }
+        }
+        let head = head.into_inner();
+        let size = match &tail {
+            Some((offset, data)) => offset + data.len() as u64,
+            None => current_off as u64,
+        };
+        Ok(CPKImage {
+            base,
+            head,
+            members,
+            tail,
+            size,
+        })
+    }
+    /// Every member listed in the TOC, with `offset` from the start of the archive.
+    pub fn files(&self) -> std::io::Result<Vec<CPKFile>> {
+        let content = value_u32(&self.utfs["CpkHeader"], 0, "TocOffset")?;
+        let toc = &self.utfs["TOC"];
+        (0..toc.rows.len())
+            .map(|i| {
+                let value = |x: &str| value_u32(toc, i, x);
+                let row = &toc.rows[i];
+                let name = row.get("FileName").and_then(|x| x.as_ref()?.as_str());
+                Ok(CPKFile {
+                    id: value("ID")?,
+                    name: name
+                        .ok_or_else(|| invalid(format!("CPK TOC row {} has no FileName", i)))?
+                        .into(),
+                    file_size: value("FileSize")?,
+                    extract_size: value("ExtractSize")?,
+                    offset: content + value("FileOffset")?,
+                    crc: row
+                        .get("CRC")
+                        .and_then(|x| x.as_ref()?.as_u32())
+                        .filter(|x| *x != 0),
+                })
+            })
+            .collect()
+    }
+    /// Reads a member into `data`, replacing its contents and decompressing
+    /// it if it is stored compressed, so one buffer can be reused for many
+    /// members.
+    pub fn read_file_into<R: std::io::Read + std::io::Seek>(
+        &self,
+        read: &mut R,
+        file: &CPKFile,
+        data: &mut Vec<u8>,
+    ) -> std::io::Result<()> {
+        if file.extract_size != file.file_size {
+            let packed = read_bytes_at(read, file.offset, file.file_size)?;
+            return Ok(crilayla_decompress_into(&packed, data)?);
+        }
+        data.clear();
+        data.resize(file.file_size as usize, 0);
+        read.seek(SeekFrom::Start(file.offset as u64))?;
+        read.read_exact(data)
     }
 
-    pub fn map_files<F, R: std::io::Read + std::io::Seek>(
-        &mut self,
+    /// Checks the table CRCs in the header and the member CRCs in the TOC
+    /// against the data in `read`. CRCs that are not stored are skipped.
+    pub fn verify<R: std::io::Read + std::io::Seek>(
+        &self,
         read: &mut R,
-        func: F,
-    ) -> std::io::Result<()>
-    where
-        F: Fn(CPKFile, Vec<u8>) -> std::io::Result<()>,
-    {
-        let content: u32 = self.utfs["CpkHeader"].rows[0]["TocOffset"]
-            .as_ref()
-            .unwrap()
-            .into();
-        let num_files = self.utfs["TOC"].rows.len();
-        for ind in 0..num_files {
-            let off: u32 = self.utfs["TOC"].rows[ind]["FileOffset"]
-                .as_ref()
-                .unwrap()
-                .into();
-            let file = CPKFile {
-                id: self.utfs["TOC"].rows[ind]["ID"].as_ref().unwrap().into(),
-                name: self.utfs["TOC"].rows[ind]["FileName"]
-                    .as_ref()
-                    .unwrap()
-                    .into(),
-                file_size: self.utfs["TOC"].rows[ind]["FileSize"]
-                    .as_ref()
-                    .unwrap()
-                    .into(),
-                extract_size: self.utfs["TOC"].rows[ind]["ExtractSize"]
-                    .as_ref()
-                    .unwrap()
-                    .into(),
-                offset: content + off,
+    ) -> std::io::Result<Vec<CrcMismatch>> {
+        let header = &self.utfs["CpkHeader"];
+        let mut mismatches = Vec::new();
+        for (table, prefix) in TABLES {
+            let field = |x: &str| header_u32(header, &format!("{}{}", prefix, x));
+            let (Some(stored), Some(offset), Some(size)) =
+                (field("Crc"), field("Offset"), field("Size"))
+            else {
+                continue;
             };
-            let mut data = read_bytes_at(read, file.offset, file.file_size)?;
-            if file.extract_size != file.file_size {
-                data = crilayla_decompress(data);
+            let computed = crc32fast::hash(&read_bytes_at(read, offset + 0x10, size)?);
+            if stored != computed {
+                mismatches.push(CrcMismatch {
+                    what: table.into(),
+                    stored,
+                    computed,
+                });
             }
-            func(file, data)?;
         }
-        Ok(())
+        for file in self.files()? {
+            if let Some(stored) = file.crc {
+                let data = read_bytes_at(read, file.offset, file.file_size)?;
+                let computed = crc32fast::hash(&data);
+                if stored != computed {
+                    mismatches.push(CrcMismatch {
+                        what: format!("{} {}", file.id, file.name),
+                        stored,
+                        computed,
+                    });
+                }
+            }
+        }
+        Ok(mismatches)
     }
 }
 impl BinaryStruct for CPK {
     fn read<R: std::io::Read + std::io::Seek>(read: &mut R) -> std::io::Result<Box<Self>> {
         let mut cpk = Box::new(Self {
             utfs: HashMap::new(),
+            replaced: HashMap::new(),
         });
         // let cpk =
         let header = read_utfpacket(read, "CPK ")?.1;
 
-        let toc_off: u32 = (&header.rows[0]["TocOffset"]).as_ref().unwrap().into();
-        let itoc_off: u32 = (&header.rows[0]["ItocOffset"]).as_ref().unwrap().into();
+        let toc_off = value_u32(&header, 0, "TocOffset")?;
+        let itoc_off = value_u32(&header, 0, "ItocOffset")?;
 
         read.seek(SeekFrom::Start(toc_off as u64))?;
         let toc = read_utfpacket(read, "TOC ")?.1;
         read.seek(SeekFrom::Start(itoc_off as u64))?;
         let itoc = read_utfpacket(read, "ITOC")?.1;
+        // Group and extra (update time) tables are optional.
+        for (name, column) in [("GTOC", "GtocOffset"), ("ETOC", "EtocOffset")] {
+            if let Some(off) = header_u32(&header, column) {
+                read.seek(SeekFrom::Start(off as u64))?;
+                cpk.utfs.insert(name.into(), read_utfpacket(read, name)?.1);
+            }
+        }
 
         cpk.utfs.insert("CpkHeader".into(), header);
         cpk.utfs.insert("TOC".into(), toc);
@@ -255,3 +681,218 @@ impl BinaryStruct for CPK {
         Ok(cpk)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A table with per-row columns and no rows.
+    fn table(name: &str, cols: &[(&str, UTFDataType)]) -> Box<UTF> {
+        let mut utf = UTF::new(name.into());
+        for (col, dtype) in cols {
+            utf.add_col(col.to_string(), *dtype, UTFStorage::PER_ROW, None);
+        }
+        Box::new(utf)
+    }
+
+    /// An archive without members, with ITOC by `TocIndex` or by `DataL`
+    /// and `DataH`.
+    fn empty_cpk(toc_index: bool) -> CPK {
+        use UTFDataType::*;
+        let mut header = table(
+            "CpkHeader",
+            &[
+                ("ContentOffset", U64),
+                ("ContentSize", U64),
+                ("TocOffset", U64),
+                ("TocSize", U64),
+                ("TocCrc", U32),
+                ("ItocOffset", U64),
+                ("ItocSize", U64),
+                ("ItocCrc", U32),
+                ("EtocOffset", U64),
+                ("EtocSize", U64),
+                ("EnabledPackedSize", U64),
+                ("EnabledDataSize", U64),
+                ("Files", U32),
+            ],
+        );
+        header.rows.push(header.default_row());
+        let toc = table(
+            "CpkTocInfo",
+            &[
+                ("DirName", STRING),
+                ("FileName", STRING),
+                ("FileSize", U32),
+                ("ExtractSize", U32),
+                ("FileOffset", U64),
+                ("ID", U32),
+                ("CRC", U32),
+            ],
+        );
+        let itoc = if toc_index {
+            table("CpkExtendId", &[("ID", U32), ("TocIndex", U32)])
+        } else {
+            let cols = [
+                ("FilesL", U32),
+                ("FilesH", U32),
+                ("DataL", BYTEARRAY),
+                ("DataH", BYTEARRAY),
+            ];
+            table("CpkItocInfo", &cols)
+        };
+        let mut etoc = table(
+            "CpkEtocInfo",
+            &[("UpdateDateTime", U64), ("LocalDir", STRING)],
+        );
+        etoc.rows.push(etoc.default_row());
+        CPK {
+            utfs: HashMap::from([
+                ("CpkHeader".into(), header),
+                ("TOC".into(), toc),
+                ("ITOC".into(), itoc),
+                ("ETOC".into(), etoc),
+            ]),
+            replaced: HashMap::new(),
+        }
+    }
+
+    /// Lays out and writes `cpk` on its own, then reads it back.
+    fn rewrite(cpk: CPK, source: &[u8]) -> (CPK, Cursor<Vec<u8>>) {
+        let image = cpk.layout(&mut Cursor::new(source), 0).unwrap();
+        let mut out = Vec::new();
+        image.write_to(&mut out, &mut Cursor::new(source)).unwrap();
+        assert_eq!(out.len() as u32, image.size());
+        let mut out = Cursor::new(out);
+        let cpk = CPK::read(&mut out).unwrap();
+        (*cpk, out)
+    }
+
+    fn itoc_sizes_of(itoc: &UTF, column: &str) -> Vec<(u32, u32)> {
+        let Some(Some(UTFValue::BYTES(data))) = itoc.rows[0].get(column) else {
+            panic!("ITOC has no {}", column);
+        };
+        let sizes = UTF::read(&mut Cursor::new(data)).unwrap();
+        (0..sizes.rows.len())
+            .map(|i| {
+                let value = |x| value_u32(&sizes, i, x).unwrap();
+                (value("ID"), value("FileSize"))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn itoc_follows_added_and_removed_files() {
+        let mut cpk = empty_cpk(true);
+        for (id, name) in [(7, "SEVEN.BIN"), (3, "THREE.BIN"), (5, "FIVE.BIN")] {
+            cpk.add_file(id, name, name.as_bytes().to_vec()).unwrap();
+        }
+        cpk.remove_file(3).unwrap();
+        let (cpk, _) = rewrite(cpk, &[]);
+        let toc_ids = cpk
+            .files()
+            .unwrap()
+            .iter()
+            .map(|x| x.id)
+            .collect::<Vec<_>>();
+        assert_eq!(toc_ids, [7, 5]);
+        let itoc = &cpk.utfs["ITOC"];
+        let rows = (0..itoc.rows.len())
+            .map(|i| {
+                let value = |x| value_u32(itoc, i, x).unwrap();
+                (value("ID"), value("TocIndex"))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(rows, [(5, 1), (7, 0)]);
+
+        let mut cpk = empty_cpk(false);
+        cpk.add_file(2, "BIG.BIN", vec![1; 0x10001]).unwrap();
+        cpk.add_file(1, "SMALL.BIN", vec![2; 0x20]).unwrap();
+        cpk.add_file(4, "GONE.BIN", vec![3; 0x30]).unwrap();
+        cpk.remove_file(4).unwrap();
+        let (cpk, _) = rewrite(cpk, &[]);
+        let itoc = &cpk.utfs["ITOC"];
+        assert_eq!(value_u32(itoc, 0, "FilesL").unwrap(), 1);
+        assert_eq!(value_u32(itoc, 0, "FilesH").unwrap(), 1);
+        assert_eq!(itoc_sizes_of(itoc, "DataL"), [(1, 0x20)]);
+        assert_eq!(itoc_sizes_of(itoc, "DataH"), [(2, 0x10001)]);
+    }
+
+    #[test]
+    fn malformed_tables_are_errors() {
+        let mut cpk = empty_cpk(true);
+        cpk.add_file(1, "A.BIN", vec![0; 4]).unwrap();
+        cpk.utfs.get_mut("TOC").unwrap().rows[0].remove("FileSize");
+        assert!(cpk.files().is_err());
+        assert!(cpk.layout(&mut Cursor::new(Vec::new()), 0).is_err());
+
+        let mut toc = empty_cpk(true).utfs.remove("TOC").unwrap();
+        toc.rows.push(toc.default_row());
+        toc.rows[0].insert("FileName".into(), Some(UTFValue::U32(1)));
+        assert!(toc.encode().is_err());
+    }
+
+    #[test]
+    fn added_files_round_trip() {
+        let mut source = empty_cpk(true);
+        source
+            .add_file(1, "OLD.BIN", b"old member".to_vec())
+            .unwrap();
+        let etoc = source.utfs.get_mut("ETOC").unwrap();
+        set_value(etoc, 0, "UpdateDateTime", 0x1234).unwrap();
+        let (_, source) = rewrite(source, &[]);
+        let source = source.into_inner();
+
+        let mut cpk = *CPK::read(&mut Cursor::new(&source)).unwrap();
+        cpk.add_file(2, "NEW.BIN", b"new member".to_vec()).unwrap();
+        let (cpk, mut image) = rewrite(cpk, &source);
+        let mut data = Vec::new();
+        let files = cpk.files().unwrap();
+        let names = files.iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["OLD.BIN", "NEW.BIN"]);
+        for (file, expected) in files.iter().zip([&b"old member"[..], b"new member"]) {
+            cpk.read_file_into(&mut image, file, &mut data).unwrap();
+            assert_eq!(data, expected);
+        }
+        let etoc = &cpk.utfs["ETOC"];
+        assert_eq!(etoc.rows.len(), 3);
+        assert_eq!(value_u32(etoc, 0, "UpdateDateTime").unwrap(), 0x1234);
+        assert_eq!(value_u32(etoc, 1, "UpdateDateTime").unwrap(), 0);
+    }
+
+    #[test]
+    fn members_are_fixed_with_a_gtoc() {
+        let mut cpk = empty_cpk(true);
+        cpk.add_file(1, "A.BIN", vec![0; 4]).unwrap();
+        cpk.utfs.insert("GTOC".into(), table("CpkGtocInfo", &[]));
+        assert!(cpk.add_file(2, "B.BIN", vec![0; 4]).is_err());
+        assert!(cpk.remove_file(1).is_err());
+        cpk.replace_file(1, vec![1; 4]).unwrap();
+    }
+
+    #[test]
+    fn crcs_are_written_and_verified() {
+        let mut cpk = empty_cpk(true);
+        cpk.add_file(1, "A.BIN", b"member data".to_vec()).unwrap();
+        let (cpk, image) = rewrite(cpk, &[]);
+        let header = &cpk.utfs["CpkHeader"];
+        assert!(header_u32(header, "TocCrc").is_some());
+        assert!(header_u32(header, "ItocCrc").is_some());
+        let file = &cpk.files().unwrap()[0];
+        assert_eq!(file.crc, Some(crc32fast::hash(b"member data")));
+        let mut image = image.into_inner();
+        assert!(cpk.verify(&mut Cursor::new(&image)).unwrap().is_empty());
+
+        image[file.offset as usize] ^= 1;
+        let toc_end = header_u32(header, "TocOffset").unwrap()
+            + 0x10
+            + header_u32(header, "TocSize").unwrap();
+        image[toc_end as usize - 1] ^= 1;
+        let mismatches = cpk.verify(&mut Cursor::new(&image)).unwrap();
+        let what = mismatches
+            .iter()
+            .map(|x| x.what.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(what, ["TOC", "1 A.BIN"]);
+    }
+}
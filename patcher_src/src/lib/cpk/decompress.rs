@@ -1,54 +1,82 @@
-pub fn crilayla_decompress(data: Vec<u8>) -> Vec<u8> {
+/// Why a CRILAYLA member could not be decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrilaylaError {
+    /// The data does not start with `CRILAYLA`.
+    BadMagic,
+    /// The header, raw prefix or bit stream ends early.
+    Truncated,
+    /// A back reference points past the data decoded so far, or copies
+    /// beyond the start of the output.
+    BackrefOutOfRange,
+    /// The header claims more output than the bit stream can encode.
+    TooLarge,
+}
+
+impl std::fmt::Display for CrilaylaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CrilaylaError::BadMagic => "not CRILAYLA compressed data",
+            CrilaylaError::Truncated => "CRILAYLA data is truncated",
+            CrilaylaError::BackrefOutOfRange => "CRILAYLA back reference is out of range",
+            CrilaylaError::TooLarge => "CRILAYLA decompressed size is too large for the data",
+        })
+    }
+}
+
+impl std::error::Error for CrilaylaError {}
+
+impl From<CrilaylaError> for std::io::Error {
+    fn from(e: CrilaylaError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Decodes into `res`, replacing its contents, so one buffer can be reused
+/// for many members.
+pub fn crilayla_decompress_into(data: &[u8], res: &mut Vec<u8>) -> Result<(), CrilaylaError> {
+    if !data.starts_with(b"CRILAYLA") {
+        return Err(CrilaylaError::BadMagic);
+    }
+    if data.len() < 0x10 + 0x100 {
+        return Err(CrilaylaError::Truncated);
+    }
     let mut buff = [0u8; 4];
     buff.copy_from_slice(&data[8..0xc]);
     let size = u32::from_le_bytes(buff) as usize;
     buff.copy_from_slice(&data[0xc..0x10]);
     let header = u32::from_le_bytes(buff) as usize;
+    let prefix = data
+        .get(header + 0x10..header + 0x110)
+        .ok_or(CrilaylaError::Truncated)?;
+
+    // Every bit decodes to at most 32 bytes: the longest lengths take 8 bits
+    // per 255 bytes. Anything bigger is a corrupt header, so don't allocate it.
+    if size > (data.len() - 0x110) * 8 * 32 {
+        return Err(CrilaylaError::TooLarge);
+    }
 
-    let mut res = vec![0; size+0x100];
-    res[0..0x100].copy_from_slice(&data[header + 0x10..header + 0x110]);
+    res.clear();
+    res.resize(size + 0x100, 0);
+    res[0..0x100].copy_from_slice(prefix);
 
-    let input_end = data.len() - 0x100 - 1;
     let output_end = 0x100 + size - 1;
-
-    let mut off = input_end;
-    let mut curr = 0;
-    let mut rem = 0;
-
-    let vle_lens = vec![2, 3, 5, 8];
+    let mut bits = BitReader {
+        data: &data[0x10..data.len() - 0x100],
+        off: data.len() - 0x110,
+        curr: 0,
+        rem: 0,
+    };
     let mut len = 0;
 
-    macro_rules! get_next_bits {
-        ($count:expr) => {{
-            get_next_bits(&data, &mut off, &mut curr, &mut rem, $count)
-            // let mut bits = 0;
-            // let mut i = 0;
-            // while i < $count {
-            //     if rem == 0 {
-            //         curr = data[off];
-            //         off -= 1;
-            //         rem = 8;
-            //     }
-            //     let to_copy = rem.min($count - i);
-            //     bits <<= to_copy;
-            //     bits |= (curr >> (rem - to_copy)) & ((1 << to_copy) - 1);
-            //     rem -= to_copy;
-            //     i += to_copy;
-            // }
-            // bits
-        }};
-    }
+    let vle_lens = [2, 3, 5, 8];
 
     while len < size {
-        // let bit;
-        let bit = get_next_bits!(1);
-        // (bit, off, curr, rem) = get_next_bits(&data, off, curr, rem, 1);
+        let bit = bits.read(1)?;
         if bit == 1 {
-            let mut backref_off =  get_next_bits!(13) + (output_end - len + 3);
+            let mut backref_off = bits.read(13)? + (output_end - len + 3);
             let mut backref_len = 3;
             for vle_level in vle_lens.iter() {
-                let level = get_next_bits!(*vle_level);
-                // (level, off, curr, rem) = get_next_bits(&data, off, curr, rem, vle_level);
+                let level = bits.read(*vle_level)?;
                 backref_len += level;
                 if level != ((1 << vle_level) - 1) {
                     break;
@@ -56,28 +84,29 @@ pub fn crilayla_decompress(data: Vec<u8>) -> Vec<u8> {
             }
             if backref_len == 3 + 3 + 7 + 31 + 255 {
                 loop {
-                    let level = get_next_bits!(8);
-                    // (level, off, curr, rem) = get_next_bits(&data, off, curr, rem, 8);
+                    let level = bits.read(8)?;
                     backref_len += level;
                     if level != 255 {
                         break;
                     }
                 }
             }
+            if backref_off > output_end || len + backref_len > size {
+                return Err(CrilaylaError::BackrefOutOfRange);
+            }
             for _ in 0..backref_len {
                 res[output_end - len] = res[backref_off];
                 backref_off -= 1;
                 len += 1;
             }
         } else {
-            let byte = get_next_bits!(8);
-            // (byte, off, curr, rem) = get_next_bits(&data, off, curr, rem, 8);
+            let byte = bits.read(8)?;
 
             res[output_end - len] = byte as u8;
             len += 1;
         }
     }
-    res
+    Ok(())
 }
 // public byte[] DecompressLegacyCRI(byte[] input, int USize)
 // {
@@ -149,29 +178,37 @@ pub fn crilayla_decompress(data: Vec<u8>) -> Vec<u8> {
 //     return result;
 // }
 
-fn get_next_bits(
-    input: &Vec<u8>,
-    offset: &mut usize,
-    curr: &mut u8,
-    rem: &mut usize,
-    count: usize,
-) -> usize {
-    let mut bits = 0;
-    let mut i = 0;
-    while i < count {
-        if *rem == 0 {
-            *curr = input[*offset];
-            *offset -= 1;
-            *rem = 8;
+/// Reads the bit stream from its last byte towards its first, most
+/// significant bit first.
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// One past the next byte to read.
+    off: usize,
+    curr: u8,
+    rem: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, count: usize) -> Result<usize, CrilaylaError> {
+        let mut bits = 0;
+        let mut i = 0;
+        while i < count {
+            if self.rem == 0 {
+                if self.off == 0 {
+                    return Err(CrilaylaError::Truncated);
+                }
+                self.off -= 1;
+                self.curr = self.data[self.off];
+                self.rem = 8;
+            }
+            let to_copy = self.rem.min(count - i);
+            bits <<= to_copy;
+            bits |= (self.curr >> (self.rem - to_copy)) as usize & ((1 << to_copy) - 1);
+            self.rem -= to_copy;
+            i += to_copy;
         }
-        let to_copy = (*rem).min(count - i);
-        bits <<= to_copy;
-        bits |= (*curr >> (*rem - to_copy)) as usize & ((1 << to_copy) - 1);
-        *rem -= to_copy;
-        i += to_copy;
+        Ok(bits)
     }
-    bits
-    // (bits, offset, curr, rem)
 }
 // private ushort get_next_bits(byte[] input, ref int offset_p, ref byte bit_pool_p, ref int bits_left_p, int bit_count)
 // {
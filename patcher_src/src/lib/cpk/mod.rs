@@ -1,4 +1,5 @@
 pub mod utf;
 pub mod cpk;
 pub mod decompress;
+pub mod compress;
 pub use cpk::*;
\ No newline at end of file
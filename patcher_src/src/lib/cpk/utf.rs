@@ -19,7 +19,8 @@ pub enum UTFValue {
     U32(u32),
     U64(u64),
     STRING(String),
-    BYTES(Box<UTF>),
+    /// Raw bytes from the data area; often a nested table, see `UTF::parse`.
+    BYTES(Vec<u8>),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -55,18 +56,24 @@ pub struct UTFColumn {
 #[derive(Debug, Clone)]
 pub struct UTF {
     pub name: String,
-    pub data_off: u32,
     pub cols: Vec<UTFColumn>,
     pub col_lookup: HashMap<String, usize>,
     pub rows: Vec<HashMap<String, Option<UTFValue>>>,
     pub col_len: u32,
     pub row_len: u16,
     pub str_len: u32,
+    pub data_len: u32,
 }
 
-impl From<u8> for UTFDataType {
-    fn from(v: u8) -> Self {
-        match v & 0xf {
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+impl TryFrom<u8> for UTFDataType {
+    type Error = std::io::Error;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        Ok(match v & 0xf {
             0 => UTFDataType::U8,
             1 => UTFDataType::U8_2,
             2 => UTFDataType::U16,
@@ -77,19 +84,21 @@ impl From<u8> for UTFDataType {
             7 => UTFDataType::U64_2,
             10 => UTFDataType::STRING,
             11 => UTFDataType::BYTEARRAY,
-            _ => unreachable!(),
-        }
+            x => return Err(invalid(format!("Unsupported UTF column type {:#x}", x))),
+        })
     }
 }
-impl From<u8> for UTFStorage {
-    fn from(v: u8) -> Self {
-        match v >> 4 {
+impl TryFrom<u8> for UTFStorage {
+    type Error = std::io::Error;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        Ok(match v >> 4 {
             0 => UTFStorage::NONE,
             1 => UTFStorage::ZERO,
             3 => UTFStorage::CONSTANT,
             5 => UTFStorage::PER_ROW,
-            _ => unreachable!(),
-        }
+            x => return Err(invalid(format!("Unsupported UTF column storage {:#x}", x))),
+        })
     }
 }
 impl Into<u8> for UTFStorage {
@@ -124,16 +133,30 @@ impl UTFValue {
             }
             UTFDataType::BYTEARRAY => {
                 let off = read.read_u32::<BigEndian>()?;
-                let _size = read.read_u32::<BigEndian>()?;
-                let here = read.stream_position()?;
-                read.seek(SeekFrom::Start((data + off).into()))?;
-                let utf = UTF::read(read)?;
-                read.seek(SeekFrom::Start(here))?;
-                Self::BYTES(utf)
+                let size = read.read_u32::<BigEndian>()?;
+                Self::BYTES(read_bytes_at(read, data + off, size)?)
             }
         })
     }
 }
+impl UTFValue {
+    /// The value of an integer column, truncated to 32 bits.
+    pub fn as_u32(&self) -> Option<u32> {
+        match self {
+            UTFValue::U8(v) => Some(*v as u32),
+            UTFValue::U16(v) => Some(*v as u32),
+            UTFValue::U32(v) => Some(*v),
+            UTFValue::U64(v) => Some(*v as u32),
+            _ => None,
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            UTFValue::STRING(v) => Some(v),
+            _ => None,
+        }
+    }
+}
 impl Into<u32> for &UTFValue {
     fn into(self) -> u32 {
         match self {
@@ -169,8 +192,8 @@ impl UTFColumn {
             read.seek(std::io::SeekFrom::Current(3))?;
             flags = read.read_u8()?;
         }
-        let dtype = flags.into();
-        let storage = flags.into();
+        let dtype = flags.try_into()?;
+        let storage = flags.try_into()?;
         let name_off = read.read_u32::<BigEndian>()?;
         let name = read_cstring_at(read, (str + name_off) as u64)?;
         let value = if let UTFStorage::CONSTANT = storage {
@@ -193,7 +216,10 @@ impl BinaryStruct for UTF {
         let base = (read.stream_position()? + 8) as u32;
         let utfname = read_string_n(read, 4)?;
         if utfname != "@UTF" {
-            panic!("Not utf");
+            return Err(invalid(format!(
+                "Expected a @UTF table, found {:?}",
+                utfname
+            )));
         }
         let table_size = read.read_u32::<BigEndian>()?;
         let rows_offset = read.read_u32::<BigEndian>()? + base;
@@ -247,13 +273,13 @@ impl BinaryStruct for UTF {
 
         Ok(Box::new(Self {
             name,
-            data_off: data_offset,
             cols,
             col_lookup,
             rows,
             str_len: 0,
             row_len,
             col_len: 0,
+            data_len: 0,
         }))
     }
 }
@@ -264,6 +290,8 @@ impl UTFValue {
         write: &mut W,
         str_off: u32,
         str_ptr: &mut u32,
+        data_off: u32,
+        data_ptr: &mut u32,
     ) -> std::io::Result<()> {
         match self {
             UTFValue::U8(v) => write.write_u8(*v),
@@ -275,13 +303,51 @@ impl UTFValue {
                 *str_ptr += write_cstring_at(write, str_off + *str_ptr, v)?;
                 write.write_u32::<BigEndian>(off)
             }
-
-            UTFValue::BYTES(_) => todo!(),
+            UTFValue::BYTES(v) => {
+                let off = *data_ptr;
+                write_bytes_at(write, data_off + *data_ptr, v)?;
+                *data_ptr += v.len() as u32;
+                write.write_u32::<BigEndian>(off)?;
+                write.write_u32::<BigEndian>(v.len() as u32)
+            }
         }
     }
 }
 
 impl UTF {
+    /// An empty table; add columns with `add_col` before any rows.
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            cols: Vec::new(),
+            col_lookup: HashMap::new(),
+            rows: Vec::new(),
+            col_len: 0,
+            row_len: 0,
+            str_len: 0,
+            data_len: 0,
+        }
+    }
+    /// A row with every stored column set to zero or empty.
+    pub fn default_row(&self) -> HashMap<String, Option<UTFValue>> {
+        self.cols
+            .iter()
+            .map(|col| {
+                let value = match col.storage {
+                    UTFStorage::PER_ROW => Some(match col.dtype {
+                        UTFDataType::U8 | UTFDataType::U8_2 => UTFValue::U8(0),
+                        UTFDataType::U16 | UTFDataType::U16_2 => UTFValue::U16(0),
+                        UTFDataType::U32 | UTFDataType::U32_2 => UTFValue::U32(0),
+                        UTFDataType::U64 | UTFDataType::U64_2 => UTFValue::U64(0),
+                        UTFDataType::STRING => UTFValue::STRING(String::new()),
+                        UTFDataType::BYTEARRAY => UTFValue::BYTES(Vec::new()),
+                    }),
+                    _ => None,
+                };
+                (col.name.clone(), value)
+            })
+            .collect()
+    }
     pub fn add_col(
         &mut self,
         name: String,
@@ -311,21 +377,19 @@ impl UTF {
     pub fn get_col_mut(&mut self, name: &str) -> &mut UTFColumn {
         self.cols.get_mut(self.col_lookup[name]).unwrap()
     }
-    pub fn remove_column(&mut self, name: &str) {
-        // dbg!(name);
-        let idx = self.col_lookup[name];
-        self.cols.remove(idx);
-        self.col_lookup.remove(name);
-        self.col_lookup.values_mut().for_each(|x| {
-            if *x > idx {
-                *x -= 1;
-            }
-        })
-    }
-    pub fn calculate_size(&mut self) -> usize {
+    /// Sizes the table for `write`. Fails if a string or byte array column
+    /// holds anything else, or a row lacks a value for a stored column.
+    pub fn calculate_size(&mut self) -> std::io::Result<usize> {
+        let mismatched = |col: &UTFColumn| {
+            invalid(format!(
+                "UTF table {} has a {:?} column {} holding other data",
+                self.name, col.dtype, col.name
+            ))
+        };
         let mut row_len = 0;
         let mut col_len = 0;
         let mut str_len = 7 + self.name.len() + 1;
+        let mut data_len = 0;
         for col in self.cols.iter() {
             col_len += 5;
             str_len += col.name.len() + 1;
@@ -346,12 +410,17 @@ impl UTF {
                             if let Some(UTFValue::STRING(v)) = &col.value {
                                 str_len += v.len() + 1;
                             } else {
-                                panic!("Mismatched data")
+                                return Err(mismatched(col));
                             }
                             4
                         }
                         UTFDataType::BYTEARRAY => {
-                            unimplemented!()
+                            if let Some(UTFValue::BYTES(v)) = &col.value {
+                                data_len += v.len();
+                            } else {
+                                return Err(mismatched(col));
+                            }
+                            8
                         }
                     }
                 }
@@ -366,17 +435,22 @@ impl UTF {
                         UTFDataType::U64 => 8,
                         UTFDataType::U64_2 => 8,
                         UTFDataType::STRING => {
-                            str_len += self.rows.iter().fold(0, |p, c| {
-                                p + if let Some(UTFValue::STRING(v)) = &c[&col.name] {
-                                    v.len() + 1
-                                } else {
-                                    panic!("Mismatched data")
+                            for row in self.rows.iter() {
+                                match row.get(&col.name) {
+                                    Some(Some(UTFValue::STRING(v))) => str_len += v.len() + 1,
+                                    _ => return Err(mismatched(col)),
                                 }
-                            });
+                            }
                             4
                         }
                         UTFDataType::BYTEARRAY => {
-                            unimplemented!()
+                            for row in self.rows.iter() {
+                                match row.get(&col.name) {
+                                    Some(Some(UTFValue::BYTES(v))) => data_len += v.len(),
+                                    _ => return Err(mismatched(col)),
+                                }
+                            }
+                            8
                         }
                     }
                 }
@@ -386,12 +460,26 @@ impl UTF {
         // assert_eq!(self.row_len, row_len);
         self.row_len = row_len;
         self.col_len = col_len;
+        self.data_len = data_len as u32;
 
         //header values, @UTF, <NULL>\0 + own name
-        align!(
-            str_len + (row_len as usize) * self.rows.len() + (col_len as usize) + 6 * 4 + 2 * 2 + 4,
-            4
-        )
+        let size =
+            str_len + (row_len as usize) * self.rows.len() + (col_len as usize) + 6 * 4 + 2 * 2 + 4;
+        if data_len == 0 {
+            Ok(align!(size, 4))
+        } else {
+            Ok(align!(align!(size, 8) + data_len, 4))
+        }
+    }
+
+    /// The table as it is written, padded to its full size.
+    pub fn encode(&mut self) -> std::io::Result<Vec<u8>> {
+        let size = self.calculate_size()?;
+        let mut out = std::io::Cursor::new(Vec::new());
+        self.write(&mut out)?;
+        let mut out = out.into_inner();
+        out.resize(size, 0);
+        Ok(out)
     }
 
     pub fn write<W: Write + Seek>(&self, write: &mut W) -> std::io::Result<()> {
@@ -411,6 +499,19 @@ impl UTF {
         let row_off = 5 * 4 + 2 * 2 + self.col_len;
         let str_off = row_off + (self.row_len as u32) * (self.rows.len() as u32);
         let mut str_ptr = 0;
+        // Byte arrays go after the strings, starting on an 8 byte boundary
+        // of the file. Without any, the data offset is the end of the table.
+        let data_off = if self.data_len == 0 {
+            size
+        } else {
+            align!(str_off + self.str_len + 8, 8) - 8
+        };
+        let size = if self.data_len == 0 {
+            size
+        } else {
+            align!(data_off + self.data_len, 4)
+        };
+        let mut data_ptr = 0;
 
         macro_rules! add_str {
             ($s:expr) => {
@@ -422,7 +523,7 @@ impl UTF {
         write.write_u32::<BigEndian>(size)?;
         write.write_u32::<BigEndian>(row_off)?;
         write.write_u32::<BigEndian>(str_off)?;
-        write.write_u32::<BigEndian>(size)?; //data offset
+        write.write_u32::<BigEndian>(data_off)?;
         str_ptr += write_cstring_at(write, (start as u32) + 8 + str_ptr + str_off, "<NULL>")?;
         add_str!(&self.name);
         write.write_u16::<BigEndian>(self.cols.len() as u16)?;
@@ -435,10 +536,18 @@ impl UTF {
             add_str!(&col.name);
             match col.storage {
                 UTFStorage::CONSTANT => {
-                    col.value.as_ref().unwrap().write(
+                    let value = col.value.as_ref().ok_or_else(|| {
+                        invalid(format!(
+                            "UTF table {} has no value for constant column {}",
+                            self.name, col.name
+                        ))
+                    })?;
+                    value.write(
                         write,
                         (start as u32) + 8 + str_off,
                         &mut str_ptr,
+                        (start as u32) + 8 + data_off,
+                        &mut data_ptr,
                     )?;
                 }
                 _ => (),
@@ -454,10 +563,19 @@ impl UTF {
             .collect::<Vec<_>>();
         for row in self.rows.iter() {
             for col in row_cols.iter() {
-                row[&col.name].as_ref().unwrap().write(
+                let value = row.get(&col.name).and_then(|x| x.as_ref());
+                let value = value.ok_or_else(|| {
+                    invalid(format!(
+                        "UTF table {} has a row without a value for column {}",
+                        self.name, col.name
+                    ))
+                })?;
+                value.write(
                     write,
                     (start as u32) + 8 + str_off,
                     &mut str_ptr,
+                    (start as u32) + 8 + data_off,
+                    &mut data_ptr,
                 )?;
             }
         }
@@ -0,0 +1,315 @@
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+
+use sha1::{Digest, Sha1};
+
+use super::cpk::CPK;
+use super::dirent::DirEnt;
+use super::iso::ISO;
+use super::util::BinaryStruct;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffKind {
+    /// Only in the second image, with its SHA-1.
+    Added(String),
+    /// Only in the first image, with its SHA-1.
+    Removed(String),
+    /// Same path, different first sector.
+    Moved { from: u32, to: u32 },
+    /// Same path, different contents, as SHA-1s.
+    Changed { from: String, to: String },
+    /// A volume descriptor field, as printed.
+    Field { from: String, to: String },
+}
+
+/// One difference between two images. `path` is a file path, `PVD/<field>`
+/// or `<cpk path>/<member id>` for members of a CPK archive.
+#[derive(Debug, Clone)]
+pub struct Difference {
+    pub path: String,
+    pub kind: DiffKind,
+}
+
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            DiffKind::Added(hash) => write!(f, "+ {} {}", self.path, hash),
+            DiffKind::Removed(hash) => write!(f, "- {} {}", self.path, hash),
+            DiffKind::Moved { from, to } => {
+                write!(f, "> {} moved from sector {} to {}", self.path, from, to)
+            }
+            DiffKind::Changed { from, to } => write!(f, "~ {} {} -> {}", self.path, from, to),
+            DiffKind::Field { from, to } => write!(f, "~ {} {:?} -> {:?}", self.path, from, to),
+        }
+    }
+}
+
+fn hash<R: Read>(read: &mut R) -> std::io::Result<String> {
+    let mut hasher = Sha1::new();
+    std::io::copy(read, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn hash_file<S: Read + Seek>(iso: &mut ISO<S>, ent: &DirEnt) -> std::io::Result<String> {
+    if (ent.flags & 2) != 0 {
+        return Ok(String::from("-"));
+    }
+    hash(&mut iso.open_file(ent)?)
+}
+
+/// Files and directories keyed by their upper-cased path.
+fn tree<S: Read + Seek>(
+    iso: &mut ISO<S>,
+) -> std::io::Result<BTreeMap<String, (String, Box<DirEnt>)>> {
+    Ok(iso
+        .walk()?
+        .into_iter()
+        .map(|(path, ent)| (path.to_ascii_uppercase(), (path, ent)))
+        .collect())
+}
+
+fn diff_pvd<A: Read + Seek, B: Read + Seek>(
+    a: &mut ISO<A>,
+    b: &mut ISO<B>,
+    diffs: &mut Vec<Difference>,
+) -> std::io::Result<()> {
+    let (a, b) = (a.get_pvd()?, b.get_pvd()?);
+    let fields = [
+        ("system_id", a.system_id.clone(), b.system_id.clone()),
+        ("volume_id", a.volume_id.clone(), b.volume_id.clone()),
+        (
+            "volume_space_size",
+            a.volume_space_size.to_string(),
+            b.volume_space_size.to_string(),
+        ),
+        (
+            "volume_set_size",
+            a.volume_set_size.to_string(),
+            b.volume_set_size.to_string(),
+        ),
+        (
+            "volume_seq_num",
+            a.volume_seq_num.to_string(),
+            b.volume_seq_num.to_string(),
+        ),
+        (
+            "path_table_size",
+            a.path_table_size.to_string(),
+            b.path_table_size.to_string(),
+        ),
+        ("l_sector", a.l_sector.to_string(), b.l_sector.to_string()),
+        ("m_sector", a.m_sector.to_string(), b.m_sector.to_string()),
+        (
+            "root_sector",
+            a.root_ent.sector.to_string(),
+            b.root_ent.sector.to_string(),
+        ),
+        ("set_id", a.set_id.clone(), b.set_id.clone()),
+        ("pub_id", a.pub_id.clone(), b.pub_id.clone()),
+        ("prep_id", a.prep_id.clone(), b.prep_id.clone()),
+        ("app_id", a.app_id.clone(), b.app_id.clone()),
+        (
+            "copyright_file",
+            a.copyright_file.clone(),
+            b.copyright_file.clone(),
+        ),
+        (
+            "abstract_file",
+            a.abstract_file.clone(),
+            b.abstract_file.clone(),
+        ),
+        ("biblio_file", a.biblio_file.clone(), b.biblio_file.clone()),
+        (
+            "created",
+            format!("{:?}", a.created),
+            format!("{:?}", b.created),
+        ),
+        (
+            "modified",
+            format!("{:?}", a.modified),
+            format!("{:?}", b.modified),
+        ),
+        (
+            "expired",
+            format!("{:?}", a.expired),
+            format!("{:?}", b.expired),
+        ),
+        (
+            "effective",
+            format!("{:?}", a.effective),
+            format!("{:?}", b.effective),
+        ),
+        (
+            "application_use",
+            String::from_utf8_lossy(&a.application_use)
+                .trim_end()
+                .to_string(),
+            String::from_utf8_lossy(&b.application_use)
+                .trim_end()
+                .to_string(),
+        ),
+    ];
+    for (name, from, to) in fields {
+        if from != to {
+            diffs.push(Difference {
+                path: format!("PVD/{}", name),
+                kind: DiffKind::Field { from, to },
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Name, offset and SHA-1 of every member of a CPK archive, by ID.
+fn cpk_members<S: Read + Seek>(
+    iso: &mut ISO<S>,
+    path: &str,
+) -> std::io::Result<BTreeMap<u32, (String, u32, String)>> {
+    let mut file = iso.open(path)?;
+    let cpk = CPK::read(&mut file)?;
+    let mut data = Vec::new();
+    cpk.files()?
+        .into_iter()
+        .map(|x| {
+            cpk.read_file_into(&mut file, &x, &mut data)?;
+            Ok((x.id, (x.name, x.offset, hash(&mut &data[..])?)))
+        })
+        .collect()
+}
+
+/// Members of a CPK archive that was changed, by ID.
+fn diff_cpk<A: Read + Seek, B: Read + Seek>(
+    path: &str,
+    a: &mut ISO<A>,
+    b: &mut ISO<B>,
+    diffs: &mut Vec<Difference>,
+) -> std::io::Result<()> {
+    let (a, b) = (cpk_members(a, path)?, cpk_members(b, path)?);
+    for (id, (name, offset, from)) in a.iter() {
+        let member = format!("{}/{} {}", path, id, name);
+        match b.get(id) {
+            None => diffs.push(Difference {
+                path: member,
+                kind: DiffKind::Removed(from.clone()),
+            }),
+            Some((_, new_offset, to)) => {
+                if offset != new_offset {
+                    diffs.push(Difference {
+                        path: member.clone(),
+                        kind: DiffKind::Moved {
+                            from: *offset,
+                            to: *new_offset,
+                        },
+                    });
+                }
+                if from != to {
+                    diffs.push(Difference {
+                        path: member,
+                        kind: DiffKind::Changed {
+                            from: from.clone(),
+                            to: to.clone(),
+                        },
+                    });
+                }
+            }
+        }
+    }
+    for (id, (name, _, to)) in b.iter() {
+        if !a.contains_key(id) {
+            diffs.push(Difference {
+                path: format!("{}/{} {}", path, id, name),
+                kind: DiffKind::Added(to.clone()),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Compares two images file by file. Files are matched by path; changed
+/// `.cpk` archives are compared member by member as well.
+pub fn diff<A: Read + Seek, B: Read + Seek>(
+    a: &mut ISO<A>,
+    b: &mut ISO<B>,
+) -> std::io::Result<Vec<Difference>> {
+    let mut diffs = Vec::new();
+    diff_pvd(a, b, &mut diffs)?;
+
+    let old = tree(a)?;
+    let mut new = tree(b)?;
+    for (key, (path, ent)) in old.into_iter() {
+        let new_ent = match new.remove(&key) {
+            Some((_, new_ent)) => new_ent,
+            None => {
+                diffs.push(Difference {
+                    kind: DiffKind::Removed(hash_file(a, &ent)?),
+                    path,
+                });
+                continue;
+            }
+        };
+        if ent.sector != new_ent.sector {
+            diffs.push(Difference {
+                path: path.clone(),
+                kind: DiffKind::Moved {
+                    from: ent.sector,
+                    to: new_ent.sector,
+                },
+            });
+        }
+        if (ent.flags & 2) != 0 || (new_ent.flags & 2) != 0 {
+            continue;
+        }
+        let (from, to) = (hash_file(a, &ent)?, hash_file(b, &new_ent)?);
+        if from != to {
+            diffs.push(Difference {
+                path: path.clone(),
+                kind: DiffKind::Changed { from, to },
+            });
+            if key.ends_with(".CPK") {
+                diff_cpk(&path, a, b, &mut diffs)?;
+            }
+        }
+    }
+    for (_, (path, ent)) in new.into_iter() {
+        diffs.push(Difference {
+            kind: DiffKind::Added(hash_file(b, &ent)?),
+            path,
+        });
+    }
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::iso::tests::{build_image, open_image, temp_dir};
+    use crate::lib::iso::BuildOptions;
+
+    #[test]
+    fn one_changed_file() {
+        let images = [b"old!", b"new!"].map(|text| {
+            let dir = temp_dir(&format!("diff_{}", text[0]));
+            std::fs::create_dir_all(dir.join("DATA")).unwrap();
+            std::fs::write(dir.join("A.BIN"), vec![1; 3000]).unwrap();
+            std::fs::write(dir.join("DATA/B.TXT"), text).unwrap();
+            let image = build_image(&dir, &BuildOptions::default());
+            std::fs::remove_dir_all(dir).unwrap();
+            image
+        });
+        let mut a = open_image(images[0].clone());
+        let mut b = open_image(images[1].clone());
+
+        let diffs = diff(&mut a, &mut b).unwrap();
+        assert_eq!(diffs.len(), 1, "{:#?}", diffs);
+        assert_eq!(diffs[0].path, "DATA/B.TXT");
+        match &diffs[0].kind {
+            DiffKind::Changed { from, to } => {
+                assert_eq!(from, &hash(&mut &b"old!"[..]).unwrap());
+                assert_eq!(to, &hash(&mut &b"new!"[..]).unwrap());
+            }
+            kind => panic!("{:?}", kind),
+        }
+        let mut same = open_image(images[0].clone());
+        assert!(diff(&mut a, &mut same).unwrap().is_empty());
+    }
+}
@@ -1,5 +1,7 @@
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use chrono::{DateTime, Datelike, Local, Timelike};
+use chrono::{
+    DateTime, Datelike, FixedOffset, Local, NaiveDate, Offset, TimeZone, Timelike, Utc,
+};
 use std::{
     alloc::System,
     fmt::Debug,
@@ -52,19 +54,60 @@ impl Debug for DirEntTime {
     }
 }
 
-impl From<SystemTime> for DirEntTime {
-    fn from(t: SystemTime) -> Self {
+impl TryFrom<SystemTime> for DirEntTime {
+    type Error = std::io::Error;
+    fn try_from(t: SystemTime) -> std::io::Result<Self> {
         let time: DateTime<Local> = DateTime::from(t);
+        Self::from_chrono(&time)
+    }
+}
 
-        Self {
-            year: time.year_ce().1 as u16,
+impl DirEntTime {
+    /// `secs` since 1970 in UTC, for timestamps that must not depend on the
+    /// local timezone (e.g. `SOURCE_DATE_EPOCH`).
+    pub fn from_epoch(secs: i64) -> std::io::Result<Self> {
+        let time = DateTime::<Utc>::from_timestamp(secs, 0).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} seconds since 1970 is not a valid date", secs),
+            )
+        })?;
+        Self::from_chrono(&time)
+    }
+    /// Fails for years a directory record can't store, which are 1900 to 2155.
+    pub fn from_chrono<Tz: TimeZone>(time: &DateTime<Tz>) -> std::io::Result<Self> {
+        if !(1900..=2155).contains(&time.year()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} is outside the years 1900 to 2155 a directory record can hold",
+                    time.year()
+                ),
+            ));
+        }
+        Ok(Self {
+            year: time.year() as u16,
             month: time.month() as u8,
             day: time.day() as u8,
             hour: time.hour() as u8,
             minute: time.minute() as u8,
             second: time.second() as u8,
-            timezone: (time.offset().local_minus_utc() / 60 / 15) as i8,
-        }
+            timezone: (time.offset().fix().local_minus_utc() / 60 / 15) as i8,
+        })
+    }
+    /// `None` for an unset (all zero) or invalid time.
+    pub fn to_chrono(&self) -> Option<DateTime<FixedOffset>> {
+        NaiveDate::from_ymd_opt(self.year as i32, self.month as u32, self.day as u32)?
+            .and_hms_opt(self.hour as u32, self.minute as u32, self.second as u32)?
+            .and_local_timezone(FixedOffset::east_opt(self.timezone as i32 * 15 * 60)?)
+            .single()
+    }
+}
+
+impl Default for DirEntTime {
+    //setting it to a constant so the patched ISO can have a consistent checksum...
+    fn default() -> Self {
+        Self { year: 2012, month: 3, day: 14, hour: 11, minute: 21, second: 00, timezone: 36 }
     }
 }
 
@@ -96,6 +139,10 @@ pub struct DirEnt {
     pub volume: u16,
     pub name: String,
     pub has_xa: bool,
+    /// Bytes after the name as read from an image (the XA record on UMDs).
+    /// Written back as they are; if there are none, `has_xa` writes a
+    /// default XA record.
+    pub system_use: Vec<u8>,
 }
 
 impl TryFrom<&std::path::Path> for DirEnt {
@@ -104,19 +151,24 @@ impl TryFrom<&std::path::Path> for DirEnt {
     fn try_from(ent: &std::path::Path) -> Result<Self, Self::Error> {
         let meta = ent.metadata()?;
         let name: String = ent.file_name().unwrap().to_str().unwrap().into();
+        let size = if meta.is_dir() { 0 } else { meta.len() as u32 };
+        Ok(Self::new(name, size, meta.is_dir()))
+    }
+}
+impl DirEnt {
+    pub fn new(name: String, size: u32, is_dir: bool) -> Self {
         let length = align!(33 + name.len(), 2) as u8;
         let ext_attr = 0;
         let sector = 0;
-        let size = if meta.is_dir() { 0 } else { meta.len() as u32 };
-        //setting it to a constant so the patched ISO can have a consistent checksum...
         // let time = Box::new(DirEntTime::from(SystemTime::now()));
-        let time = Box::new(DirEntTime { year: 2012, month: 3, day: 14, hour: 11, minute: 21, second: 00, timezone: 36 });
-        let flags = if meta.is_dir() { 0x2 } else { 0 };
+        let time = Box::new(DirEntTime::default());
+        let flags = if is_dir { 0x2 } else { 0 };
         let unit_size = 0;
         let gap = 0;
         let volume = 1;
         let has_xa = false;
-        Ok(Self {
+        let system_use = Vec::new();
+        Self {
             length,
             ext_attr,
             sector,
@@ -128,24 +180,26 @@ impl TryFrom<&std::path::Path> for DirEnt {
             volume,
             name,
             has_xa,
-        })
+            system_use,
+        }
     }
 }
 impl DirEnt {
     pub fn update_length(&mut self) {
         self.length = align!(33 + self.name.len(), 2) as u8;
-        if self.has_xa {
+        if !self.system_use.is_empty() {
+            self.length += self.system_use.len() as u8;
+        } else if self.has_xa {
             self.length += 14;
         }
     }
+    /// Turning XA off also drops any system use bytes from the source.
     pub fn set_xa(&mut self, xa: bool) {
-        match (self.has_xa, xa) {
-            (true, true) => (),
-            (true, false) => self.length -= 14,
-            (false, true) => self.length += 14,
-            (false, false) => (),
-        }
         self.has_xa = xa;
+        if !xa {
+            self.system_use.clear();
+        }
+        self.update_length();
     }
     pub fn set_size(&mut self, size: u32) {
         self.size = size;
@@ -165,7 +219,9 @@ impl DirEnt {
         if (self.name.len() & 1) == 0 {
             write.write_u8(0)?;
         }
-        if self.has_xa {
+        if !self.system_use.is_empty() {
+            write.write_all(&self.system_use)?;
+        } else if self.has_xa {
             write.write_u32::<LittleEndian>(0)?;
             write.write_u8(0x8d)?;
             write_string(write, "UXA")?;
@@ -192,6 +248,11 @@ impl BinaryStruct for DirEnt {
         let name = read_string_n(read, name_len as usize)?;
 
         let end = read.stream_position()?;
+        let pad = 1 - (name_len as u64 & 1);
+        let system_use_len = (length as u64).saturating_sub(end - start + pad);
+        read.seek(std::io::SeekFrom::Current(pad as i64))?;
+        let mut system_use = vec![0u8; system_use_len as usize];
+        read.read_exact(&mut system_use)?;
 
         read.seek(std::io::SeekFrom::Start(start + length as u64))?;
         Ok(Box::new(Self {
@@ -206,6 +267,22 @@ impl BinaryStruct for DirEnt {
             volume,
             name,
             has_xa: (length as u64) != end - start,
+            system_use,
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_chrono_rejects_years_a_record_cannot_hold() {
+        let year = |y| Utc.with_ymd_and_hms(y, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(DirEntTime::from_chrono(&year(1900)).unwrap().year, 1900);
+        assert_eq!(DirEntTime::from_chrono(&year(2155)).unwrap().year, 2155);
+        assert!(DirEntTime::from_chrono(&year(1899)).is_err());
+        assert!(DirEntTime::from_chrono(&year(2156)).is_err());
+        assert!(DirEntTime::from_epoch(i64::MAX).is_err());
+    }
+}
@@ -0,0 +1,627 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::prelude::*;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use crate::align;
+
+use super::iso::ISO;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// One problem found by `check`. `location` is an image path, or the
+/// structure the problem is in (`PVD`, `L path table`).
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub severity: Severity,
+    pub location: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}: {}: {}", severity, self.location, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn error(&mut self, location: &str, message: String) {
+        self.0.push(Finding {
+            severity: Severity::Error,
+            location: location.to_string(),
+            message,
+        });
+    }
+    fn warning(&mut self, location: &str, message: String) {
+        self.0.push(Finding {
+            severity: Severity::Warning,
+            location: location.to_string(),
+            message,
+        });
+    }
+}
+
+/// Directory record fields as stored, without the both-endian check that
+/// `DirEnt::read` does.
+struct Record {
+    length: usize,
+    sector: u32,
+    size: u32,
+    flags: u8,
+    name: Vec<u8>,
+    system_use: Vec<u8>,
+}
+
+/// Parses the record at the start of `data`, reporting field problems
+/// against `location`. Returns `None` if the record cannot be walked.
+fn parse_record(data: &[u8], location: &str, findings: &mut Findings) -> Option<Record> {
+    let length = data[0] as usize;
+    if length < 34 || length > data.len() {
+        findings.error(location, format!("Bad record length {}", length));
+        return None;
+    }
+    let name_len = data[32] as usize;
+    let name_end = 33 + name_len + (1 - (name_len & 1));
+    if name_end > length {
+        findings.error(
+            location,
+            format!(
+                "Name of {} bytes does not fit in a {} byte record",
+                name_len, length
+            ),
+        );
+        return None;
+    }
+    if (name_len & 1) == 0 && data[33 + name_len] != 0 {
+        findings.warning(location, "Padding byte after the name is not zero".into());
+    }
+    if (length & 1) == 1 {
+        findings.warning(location, format!("Record length {} is odd", length));
+    }
+    let sector = both_endian_u32(&data[2..10], location, "location", findings);
+    let size = both_endian_u32(&data[10..18], location, "data length", findings);
+    both_endian_u16(&data[28..32], location, "volume sequence number", findings);
+    Some(Record {
+        length,
+        sector,
+        size,
+        flags: data[25],
+        name: data[33..33 + name_len].to_vec(),
+        system_use: data[name_end..length].to_vec(),
+    })
+}
+
+fn both_endian_u32(data: &[u8], location: &str, field: &str, findings: &mut Findings) -> u32 {
+    let le = LittleEndian::read_u32(&data[0..4]);
+    let be = BigEndian::read_u32(&data[4..8]);
+    if le != be {
+        findings.error(
+            location,
+            format!("{} disagrees: {:#x} (LE) vs {:#x} (BE)", field, le, be),
+        );
+    }
+    le
+}
+
+fn both_endian_u16(data: &[u8], location: &str, field: &str, findings: &mut Findings) -> u16 {
+    let le = LittleEndian::read_u16(&data[0..2]);
+    let be = BigEndian::read_u16(&data[2..4]);
+    if le != be {
+        findings.error(
+            location,
+            format!("{} disagrees: {:#x} (LE) vs {:#x} (BE)", field, le, be),
+        );
+    }
+    le
+}
+
+/// The sectors of an extent, or `None` with a finding if they are past the
+/// end of the image or can't be read.
+fn read_extent<S: Read + Seek>(
+    iso: &mut ISO<S>,
+    image_size: u64,
+    (sector, size): (u32, u32),
+    location: &str,
+    findings: &mut Findings,
+) -> Option<Vec<u8>> {
+    let len = align!(size.max(1) as u64, 2048);
+    if sector as u64 * 2048 + len > image_size {
+        findings.error(
+            location,
+            format!(
+                "Extent at sector {} ({} bytes) is past the end of the image",
+                sector, size
+            ),
+        );
+        return None;
+    }
+    let mut data = vec![0u8; len as usize];
+    for (i, chunk) in data.chunks_mut(2048).enumerate() {
+        let mut buff = [0u8; 2048];
+        if let Err(e) = iso.read_sector(sector as u64 + i as u64, &mut buff) {
+            findings.error(
+                location,
+                format!("Can't read sector {}: {}", sector as u64 + i as u64, e),
+            );
+            return None;
+        }
+        chunk.copy_from_slice(&buff);
+    }
+    Some(data)
+}
+
+/// Path table entry: directory number of the parent and the first sector.
+struct PathEntry {
+    name: Vec<u8>,
+    sector: u32,
+    parent: u16,
+}
+
+fn parse_path_table<B: ByteOrder>(
+    data: &[u8],
+    location: &str,
+    findings: &mut Findings,
+) -> Vec<PathEntry> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        if pos + 8 > data.len() {
+            findings.error(location, format!("Truncated entry at offset {}", pos));
+            break;
+        }
+        let name_len = data[pos] as usize;
+        let end = pos + 8 + name_len;
+        if name_len == 0 || end > data.len() {
+            findings.error(location, format!("Bad entry at offset {}", pos));
+            break;
+        }
+        entries.push(PathEntry {
+            name: data[pos + 8..end].to_vec(),
+            sector: B::read_u32(&data[pos + 2..pos + 6]),
+            parent: B::read_u16(&data[pos + 6..pos + 8]),
+        });
+        pos = align!(end, 2);
+    }
+    entries
+}
+
+/// Full paths (`PSP_GAME/USRDIR`) of every path table entry, by directory
+/// number. Parents must come before their children.
+fn path_table_dirs(entries: &[PathEntry], location: &str, findings: &mut Findings) -> Vec<String> {
+    let mut paths: Vec<String> = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        let number = i + 1;
+        let parent = entry.parent as usize;
+        if number == 1 {
+            if parent != 1 {
+                findings.error(location, format!("Root entry has parent {}", parent));
+            }
+            paths.push(String::new());
+        } else if parent == 0 || parent >= number {
+            findings.error(
+                location,
+                format!(
+                    "Directory {} has parent {}, which does not precede it",
+                    number, parent
+                ),
+            );
+            paths.push(String::from_utf8_lossy(&entry.name).into_owned());
+        } else {
+            let name = String::from_utf8_lossy(&entry.name);
+            let path = match paths[parent - 1].as_str() {
+                "" => name.into_owned(),
+                parent => format!("{}/{}", parent, name),
+            };
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+fn check_path_tables<S: Read + Seek>(
+    iso: &mut ISO<S>,
+    image_size: u64,
+    table_size: u32,
+    sectors: [(u32, &str); 4],
+    findings: &mut Findings,
+) -> Option<Vec<(String, u32)>> {
+    let mut tables = Vec::new();
+    for (i, (sector, location)) in sectors.into_iter().enumerate() {
+        // Optional tables are absent when their sector is zero.
+        if sector == 0 {
+            if i == 0 || i == 2 {
+                findings.error(location, "Missing".into());
+            }
+            tables.push(None);
+            continue;
+        }
+        let Some(mut data) = read_extent(iso, image_size, (sector, table_size), location, findings)
+        else {
+            tables.push(None);
+            continue;
+        };
+        data.truncate(table_size as usize);
+        let entries = if i < 2 {
+            parse_path_table::<LittleEndian>(&data, location, findings)
+        } else {
+            parse_path_table::<BigEndian>(&data, location, findings)
+        };
+        tables.push(Some((data, entries)));
+    }
+    for (copy, original) in [(1, 0), (3, 2)] {
+        if let (Some(a), Some(b)) = (&tables[copy], &tables[original]) {
+            if a.0 != b.0 {
+                findings.error(
+                    sectors[copy].1,
+                    format!("Differs from the {}", sectors[original].1),
+                );
+            }
+        }
+    }
+    let (l, m) = match (&tables[0], &tables[2]) {
+        (Some(l), Some(m)) => (&l.1, &m.1),
+        (Some(l), None) => (&l.1, &l.1),
+        (None, Some(m)) => (&m.1, &m.1),
+        (None, None) => return None,
+    };
+    if l.len() != m.len() {
+        findings.error(
+            sectors[2].1,
+            format!(
+                "Has {} entries but the {} has {}",
+                m.len(),
+                sectors[0].1,
+                l.len()
+            ),
+        );
+    }
+    for (i, (a, b)) in l.iter().zip(m.iter()).enumerate() {
+        if a.name != b.name || a.sector != b.sector || a.parent != b.parent {
+            findings.error(
+                sectors[2].1,
+                format!("Entry {} does not match the {}", i + 1, sectors[0].1),
+            );
+        }
+    }
+    let (entries, first) = (l, sectors[0].1);
+    let paths = path_table_dirs(entries, first, findings);
+    Some(
+        paths
+            .into_iter()
+            .zip(entries.iter().map(|x| x.sector))
+            .collect(),
+    )
+}
+
+/// Checks the structure of an image and reports everything that is wrong
+/// with it rather than stopping at the first problem, including extents
+/// that can't be read. Only an image whose size can't be read is returned as
+/// an error.
+pub fn check<S: Read + Seek>(iso: &mut ISO<S>) -> std::io::Result<Vec<Finding>> {
+    let mut findings = Findings::default();
+    let image_size = iso.image_size()?;
+
+    let mut pvd = [0u8; 2048];
+    if iso.read_sector(16, &mut pvd).is_err() {
+        findings.error(
+            "PVD",
+            "Image is too short to have a volume descriptor".into(),
+        );
+        return Ok(findings.0);
+    }
+    if pvd[0] != 1 || &pvd[1..6] != b"CD001" {
+        findings.error("PVD", "Sector 16 is not a primary volume descriptor".into());
+        return Ok(findings.0);
+    }
+    let volume_space_size =
+        both_endian_u32(&pvd[80..88], "PVD", "Volume space size", &mut findings);
+    both_endian_u16(&pvd[120..124], "PVD", "Volume set size", &mut findings);
+    both_endian_u16(
+        &pvd[124..128],
+        "PVD",
+        "Volume sequence number",
+        &mut findings,
+    );
+    let block_size = both_endian_u16(&pvd[128..132], "PVD", "Logical block size", &mut findings);
+    let table_size = both_endian_u32(&pvd[132..140], "PVD", "Path table size", &mut findings);
+    if block_size != 2048 {
+        findings.error("PVD", format!("Logical block size is {}", block_size));
+    }
+    let volume_bytes = volume_space_size as u64 * 2048;
+    if volume_bytes > image_size {
+        findings.error(
+            "PVD",
+            format!(
+                "Volume space size of {} sectors is past the end of the {} byte image",
+                volume_space_size, image_size
+            ),
+        );
+    } else if volume_bytes < image_size {
+        findings.warning(
+            "PVD",
+            format!(
+                "Image has {} bytes after the {} sector volume",
+                image_size - volume_bytes,
+                volume_space_size
+            ),
+        );
+    }
+
+    let tables = check_path_tables(
+        iso,
+        image_size,
+        table_size,
+        [
+            (LittleEndian::read_u32(&pvd[140..144]), "L path table"),
+            (
+                LittleEndian::read_u32(&pvd[144..148]),
+                "Optional L path table",
+            ),
+            (BigEndian::read_u32(&pvd[148..152]), "M path table"),
+            (BigEndian::read_u32(&pvd[152..156]), "Optional M path table"),
+        ],
+        &mut findings,
+    );
+
+    let root = match parse_record(&pvd[156..190], "Root", &mut findings) {
+        Some(root) => root,
+        None => return Ok(findings.0),
+    };
+
+    // Every extent in the tree, for the overlap check.
+    let mut extents: Vec<(u32, u32, String)> = Vec::new();
+    let mut dirs: HashMap<String, u32> = HashMap::new();
+    let mut visited = HashSet::new();
+    let mut q = VecDeque::new();
+    q.push_back((String::new(), root.sector, root.size, root.sector));
+    while let Some((path, sector, size, parent)) = q.pop_front() {
+        let location = if path.is_empty() { "/" } else { &path };
+        if !visited.insert(sector) {
+            findings.error(
+                location,
+                format!("Directory at sector {} is linked twice", sector),
+            );
+            continue;
+        }
+        dirs.insert(path.clone(), sector);
+        let sectors = align!(size as u64, 2048) / 2048;
+        if sector as u64 + sectors > volume_space_size as u64 {
+            findings.error(location, "Directory extent is outside the volume".into());
+            continue;
+        }
+        extents.push((sector, sectors as u32, path.clone()));
+        let Some(data) = read_extent(iso, image_size, (sector, size), location, &mut findings)
+        else {
+            continue;
+        };
+
+        let mut pos = 0;
+        let mut index = 0;
+        while pos < align!(size as usize, 2048) {
+            if data[pos] == 0 {
+                let next = align!(pos + 1, 2048);
+                if data[pos..next].iter().any(|x| *x != 0) {
+                    findings.error(
+                        location,
+                        format!("Non-zero bytes in the padding after offset {}", pos),
+                    );
+                }
+                pos = next;
+                continue;
+            }
+            if (pos % 2048) + data[pos] as usize > 2048 {
+                findings.error(
+                    location,
+                    format!("Record at offset {} crosses a sector", pos),
+                );
+            }
+            let record = match parse_record(&data[pos..], location, &mut findings) {
+                Some(record) => record,
+                None => break,
+            };
+            pos += record.length;
+            index += 1;
+
+            if record.system_use.len() >= 14 && &record.system_use[6..8] != b"XA" {
+                findings.warning(location, "System use area is not an XA record".into());
+            }
+            match index {
+                1 => {
+                    if record.name != [0] {
+                        findings.error(location, "First record is not `.`".into());
+                    } else if record.sector != sector {
+                        findings.error(
+                            location,
+                            format!("`.` points at {} instead of {}", record.sector, sector),
+                        );
+                    }
+                    continue;
+                }
+                2 => {
+                    if record.name != [1] {
+                        findings.error(location, "Second record is not `..`".into());
+                    } else if record.sector != parent {
+                        findings.error(
+                            location,
+                            format!("`..` points at {} instead of {}", record.sector, parent),
+                        );
+                    }
+                    continue;
+                }
+                _ => (),
+            }
+
+            let name = String::from_utf8_lossy(&record.name).into_owned();
+            let child = if path.is_empty() {
+                name
+            } else {
+                format!("{}/{}", path, name)
+            };
+            if (record.flags & 2) != 0 {
+                q.push_back((child, record.sector, record.size, sector));
+            } else if record.size > 0 {
+                let sectors = align!(record.size as u64, 2048) / 2048;
+                if record.sector < 18 || record.sector as u64 + sectors > volume_space_size as u64 {
+                    findings.error(
+                        &child,
+                        format!(
+                            "Extent at sector {} ({} sectors) is outside the volume",
+                            record.sector, sectors
+                        ),
+                    );
+                } else {
+                    extents.push((record.sector, sectors as u32, child));
+                }
+            }
+        }
+        if index < 2 {
+            findings.error(location, "Directory is missing `.` or `..`".into());
+        }
+    }
+
+    extents.sort_by_key(|x| x.0);
+    for pair in extents.windows(2) {
+        let (a, b) = (&pair[0], &pair[1]);
+        if b.0 < a.0 + a.1 {
+            findings.error(&b.2, format!("Extent overlaps {}", a.2));
+        }
+    }
+
+    if let Some(tables) = tables {
+        for (path, sector) in tables.iter() {
+            let location = if path.is_empty() { "/" } else { path };
+            match dirs.remove(path) {
+                Some(actual) if actual != *sector => findings.error(
+                    location,
+                    format!(
+                        "Path table has sector {} but the record has {}",
+                        sector, actual
+                    ),
+                ),
+                Some(_) => (),
+                None => findings.error(location, "In the path table but not in the tree".into()),
+            }
+        }
+        let mut missing = dirs.into_keys().collect::<Vec<_>>();
+        missing.sort();
+        for path in missing {
+            findings.error(&path, "Directory is missing from the path table".into());
+        }
+    }
+
+    Ok(findings.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::lib::iso::tests::{build_image, temp_dir};
+    use crate::lib::iso::BuildOptions;
+
+    fn errors(image: &[u8]) -> Vec<String> {
+        let mut iso = ISO::from_reader(Cursor::new(image)).unwrap();
+        check(&mut iso)
+            .unwrap()
+            .into_iter()
+            .filter(|x| x.severity == Severity::Error)
+            .map(|x| x.to_string())
+            .collect()
+    }
+
+    /// Offset of the directory record of the file `name`.
+    fn record(image: &[u8], name: &str) -> usize {
+        let mut needle = vec![name.len() as u8];
+        needle.extend_from_slice(name.as_bytes());
+        let pos = image.windows(needle.len()).position(|x| x == needle);
+        pos.unwrap() + 1 - 33
+    }
+
+    fn assert_finds(image: &[u8], message: &str) {
+        let errors = errors(image);
+        assert!(
+            errors.iter().any(|x| x.contains(message)),
+            "{:?} not in {:#?}",
+            message,
+            errors
+        );
+    }
+
+    #[test]
+    fn known_bad_images() {
+        let dir = temp_dir("fsck");
+        std::fs::create_dir_all(dir.join("DIR")).unwrap();
+        std::fs::write(dir.join("A.BIN"), vec![1; 3000]).unwrap();
+        std::fs::write(dir.join("B.BIN"), vec![2; 3000]).unwrap();
+        std::fs::write(dir.join("DIR/C.BIN"), vec![3; 10]).unwrap();
+        let image = build_image(&dir, &BuildOptions::default());
+        std::fs::remove_dir_all(dir).unwrap();
+        assert_eq!(errors(&image), Vec::<String>::new());
+
+        let mut bad = image.clone();
+        bad[16 * 2048 + 87] ^= 1;
+        assert_finds(&bad, "Volume space size disagrees");
+
+        // B.BIN pointing into A.BIN's extent.
+        let (a, b) = (record(&image, "A.BIN"), record(&image, "B.BIN"));
+        let mut bad = image.clone();
+        bad.copy_within(a + 2..a + 10, b + 2);
+        assert_finds(&bad, "Extent overlaps");
+
+        // C.BIN running past the end of the volume.
+        let c = record(&image, "C.BIN");
+        let mut bad = image.clone();
+        bad[c + 2..c + 6].copy_from_slice(&0x1000u32.to_le_bytes());
+        bad[c + 6..c + 10].copy_from_slice(&0x1000u32.to_be_bytes());
+        assert_finds(&bad, "outside the volume");
+
+        // DIR's entry in both L path tables pointing at the wrong sector.
+        let mut bad = image.clone();
+        for sector in [18, 19] {
+            let at = sector * 2048 + 10 + 2;
+            bad[at..at + 4].copy_from_slice(&1u32.to_le_bytes());
+        }
+        assert_finds(&bad, "Path table has sector");
+
+        // The M path tables and DIR's extent past the end of the image.
+        let mut bad = image.clone();
+        for at in [148, 152] {
+            let at = 16 * 2048 + at;
+            bad[at..at + 4].copy_from_slice(&0x10000u32.to_be_bytes());
+        }
+        let dir = record(&image, "DIR");
+        bad[dir + 2..dir + 6].copy_from_slice(&0x10000u32.to_le_bytes());
+        bad[dir + 6..dir + 10].copy_from_slice(&0x10000u32.to_be_bytes());
+        bad[16 * 2048 + 80..16 * 2048 + 84].copy_from_slice(&0x20000u32.to_le_bytes());
+        bad[16 * 2048 + 84..16 * 2048 + 88].copy_from_slice(&0x20000u32.to_be_bytes());
+        let found = errors(&bad);
+        let past_end = found
+            .iter()
+            .filter(|x| x.contains("past the end of the image"));
+        assert_eq!(past_end.count(), 3, "{:#?}", found);
+
+        assert_eq!(
+            errors(&image[..16 * 2048]),
+            ["error: PVD: Image is too short to have a volume descriptor"]
+        );
+
+        let mut bad = image.clone();
+        bad[16 * 2048] = 0;
+        assert_eq!(
+            errors(&bad),
+            ["error: PVD: Sector 16 is not a primary volume descriptor"]
+        );
+    }
+}
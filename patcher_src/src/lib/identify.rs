@@ -0,0 +1,234 @@
+use std::io::prelude::*;
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+use sha1::{Digest, Sha1};
+
+use super::iso::ISO;
+
+/// One line of `known_dumps.txt`:
+///
+/// ```text
+/// <good|bad> <variant> <target|*> <sha1>
+/// ```
+///
+/// `target` is a path inside the image, or `*` for the whole decoded image.
+/// Lines starting with `#` are comments.
+#[derive(Debug, Clone)]
+pub struct KnownDump {
+    pub good: bool,
+    pub variant: String,
+    pub target: String,
+    pub sha1: String,
+}
+
+#[derive(Debug, Default)]
+pub struct KnownDumps {
+    pub entries: Vec<KnownDump>,
+}
+
+/// What a source image turned out to be: its disc id and the SHA-1 of each
+/// hashed target, `*` being the whole image.
+#[derive(Debug)]
+pub struct Identity {
+    pub variant: String,
+    pub hashes: Vec<(String, String)>,
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+fn hash<R: Read>(read: &mut R) -> std::io::Result<String> {
+    let mut hasher = Sha1::new();
+    std::io::copy(read, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Reads `DISC_ID` out of a PARAM.SFO.
+pub fn disc_id(param: &[u8]) -> std::io::Result<String> {
+    if param.len() < 0x14 || &param[0..4] != b"\0PSF" {
+        return Err(invalid("PARAM.SFO is not an SFO file".into()));
+    }
+    let key_table = LittleEndian::read_u32(&param[8..12]) as usize;
+    let data_table = LittleEndian::read_u32(&param[12..16]) as usize;
+    let count = LittleEndian::read_u32(&param[16..20]) as usize;
+    for i in 0..count {
+        let entry = param
+            .get(0x14 + i * 16..0x14 + (i + 1) * 16)
+            .ok_or_else(|| invalid("PARAM.SFO is truncated".into()))?;
+        let key_start = key_table + LittleEndian::read_u16(&entry[0..2]) as usize;
+        let key = param
+            .get(key_start..)
+            .and_then(|x| x.split(|c| *c == 0).next())
+            .ok_or_else(|| invalid("PARAM.SFO is truncated".into()))?;
+        if key != b"DISC_ID" {
+            continue;
+        }
+        let len = LittleEndian::read_u32(&entry[4..8]) as usize;
+        let start = data_table + LittleEndian::read_u32(&entry[12..16]) as usize;
+        let value = param
+            .get(start..start + len)
+            .ok_or_else(|| invalid("PARAM.SFO is truncated".into()))?;
+        return Ok(String::from_utf8_lossy(value)
+            .trim_end_matches('\0')
+            .to_string());
+    }
+    Err(invalid("PARAM.SFO has no DISC_ID".into()))
+}
+
+/// Reads the disc id from `param_path` and hashes the `targets` that `known`
+/// lists for that disc, so large files nobody has hashes for are not read.
+/// The whole image is only hashed when `known` lists bad image hashes for
+/// this disc and none of the targets already matched a good dump.
+pub fn identify<S: Read + Seek>(
+    iso: &mut ISO<S>,
+    param_path: &str,
+    targets: &[&str],
+    known: &KnownDumps,
+) -> std::io::Result<Identity> {
+    let variant = disc_id(&iso.read_file(param_path)?)?;
+    let mut hashes = Vec::new();
+    for target in targets.iter().filter(|x| known.lists(&variant, x)) {
+        hashes.push((target.to_string(), hash(&mut iso.open(target)?)?));
+    }
+    if known.needs_image_hash(&variant, &hashes) {
+        hashes.push((String::from("*"), hash(&mut iso.open_image()?)?));
+    }
+    Ok(Identity { variant, hashes })
+}
+
+impl KnownDumps {
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+    pub fn parse(text: &str) -> std::io::Result<Self> {
+        let mut entries = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let good = match fields.first() {
+                Some(&"good") => true,
+                Some(&"bad") => false,
+                _ => {
+                    return Err(invalid(format!(
+                        "known_dumps.txt:{}: expected good or bad",
+                        i + 1
+                    )))
+                }
+            };
+            if fields.len() != 4 || fields[3].len() != 40 {
+                return Err(invalid(format!(
+                    "known_dumps.txt:{}: expected <good|bad> <variant> <target|*> <sha1>",
+                    i + 1
+                )));
+            }
+            entries.push(KnownDump {
+                good,
+                variant: fields[1].to_string(),
+                target: fields[2].to_string(),
+                sha1: fields[3].to_ascii_lowercase(),
+            });
+        }
+        Ok(Self { entries })
+    }
+
+    /// Whether any hash is listed for `target` of `variant`.
+    fn lists(&self, variant: &str, target: &str) -> bool {
+        self.entries
+            .iter()
+            .any(|x| x.variant == variant && x.target == target)
+    }
+
+    /// Whether the whole image has to be hashed: only if a bad image hash is
+    /// listed for `variant` and no target hash identifies a good dump.
+    fn needs_image_hash(&self, variant: &str, hashes: &[(String, String)]) -> bool {
+        let entries = self.entries.iter().filter(|x| x.variant == variant);
+        let mut bad_images = false;
+        for entry in entries {
+            if entry.target == "*" {
+                bad_images |= !entry.good;
+            } else if entry.good
+                && hashes
+                    .iter()
+                    .any(|(t, h)| *t == entry.target && *h == entry.sha1)
+            {
+                return false;
+            }
+        }
+        bad_images
+    }
+
+    /// Refuses a source that is not one of `supported`, matches a known bad
+    /// dump, or does not match any of the good hashes listed for a target.
+    pub fn check(&self, identity: &Identity, supported: &[&str]) -> std::io::Result<()> {
+        if !supported.contains(&identity.variant.as_str()) {
+            return Err(invalid(format!(
+                "{} is not supported. This patch needs one of: {}.",
+                identity.variant,
+                supported.join(", ")
+            )));
+        }
+        for (target, sha1) in identity.hashes.iter() {
+            let what = if target == "*" { "image" } else { target };
+            let known = self
+                .entries
+                .iter()
+                .filter(|x| x.variant == identity.variant && &x.target == target)
+                .collect::<Vec<_>>();
+            if known.iter().any(|x| !x.good && &x.sha1 == sha1) {
+                return Err(invalid(format!(
+                    "The {} of this {} dump is a known bad or modified dump ({}). Please use a clean dump.",
+                    what, identity.variant, sha1
+                )));
+            }
+            let good = known.iter().filter(|x| x.good).collect::<Vec<_>>();
+            // Whole-image hashes only vouch for a dump; a different container
+            // or padding is fine as long as the patched files match.
+            if target != "*" && !good.is_empty() && !good.iter().any(|x| &x.sha1 == sha1) {
+                return Err(invalid(format!(
+                    "{} does not match a clean {} dump ({}). The dump is modified or damaged.",
+                    what, identity.variant, sha1
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EBOOT: &str = "PSP_GAME/SYSDIR/EBOOT.BIN";
+    const CPK: &str = "PSP_GAME/USRDIR/pack/P2PT_ALL.cpk";
+
+    #[test]
+    fn only_listed_targets_are_hashed() {
+        let good = "1".repeat(40);
+        let known = KnownDumps::parse(&format!(
+            "# comment\ngood NPJH50581 {} {}\nbad NPJH50581 * {}\n",
+            EBOOT,
+            good,
+            "2".repeat(40)
+        ))
+        .unwrap();
+        assert!(known.lists("NPJH50581", EBOOT));
+        assert!(!known.lists("NPJH50581", CPK));
+        assert!(!known.lists("ULJM06081", EBOOT));
+
+        let matched = [(EBOOT.to_string(), good.clone())];
+        assert!(!known.needs_image_hash("NPJH50581", &matched));
+        let modified = [(EBOOT.to_string(), "3".repeat(40))];
+        assert!(known.needs_image_hash("NPJH50581", &modified));
+        let identity = Identity {
+            variant: "NPJH50581".into(),
+            hashes: modified.to_vec(),
+        };
+        assert!(known.check(&identity, &["NPJH50581"]).is_err());
+        assert!(KnownDumps::parse("good NPJH50581 *").is_err());
+    }
+}
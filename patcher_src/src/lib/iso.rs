@@ -1,75 +1,432 @@
 use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::fs::{File, OpenOptions};
-use std::io::{prelude::*, SeekFrom};
+use std::io::{prelude::*, BufWriter, Cursor, SeekFrom};
 use std::path::{Path, PathBuf};
 
-use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
 use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use glob::{MatchOptions, Pattern};
 
 use crate::align;
-use crate::lib::util::write_string_pad;
 
-use super::dirent::{self, DirEnt};
+use super::block::BlockDevice;
+use super::checksum::{Checksums, HashingWriter};
+use super::ciso::{CSOOptions, CSOWriter};
+use super::dirent::{self, DirEnt, DirEntTime};
 // use super::endian::*;
+use super::overlay::{Overlay, OverlayData, Stream};
 use super::pvd::*;
-use super::util::{write_string, BinaryStruct};
+use super::sort::SortFile;
+use super::util::{write_string, write_u32bi, BinaryStruct};
 
 #[derive(Debug)]
-pub struct ISO {
-    fp: File,
+pub struct ISO<S = File> {
+    fp: BlockDevice<S>,
 }
 
 // enum ISODirentType {
 //     Directory(Vec<Box<ISODirent>>)
 // }
 
-const WRITE_FILES: bool = true;
+/// Which files `ISO::extract_with` writes out. Patterns are globs over the
+/// path from the image root (`PSP_GAME/USRDIR/pack/*.cpk`), matched
+/// case-insensitively. A pattern that matches a directory covers everything
+/// below it.
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    /// Files to extract; everything if empty.
+    pub include: Vec<Pattern>,
+    /// Files and directories to skip, even if they are included.
+    pub exclude: Vec<Pattern>,
+    /// Sets each file's modification time to its directory record's time.
+    pub preserve_times: bool,
+}
+
+impl ExtractOptions {
+    /// Whether `path` or one of its parent directories matches any of `patterns`.
+    fn matches(patterns: &[Pattern], path: &str) -> bool {
+        let options = MatchOptions {
+            case_sensitive: false,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        path.match_indices('/')
+            .map(|(i, _)| &path[0..i])
+            .chain(std::iter::once(path))
+            .any(|x| patterns.iter().any(|p| p.matches_with(x, options)))
+    }
+}
+
+/// Container written by the `build_*` methods.
+#[derive(Debug, Clone)]
+pub enum ImageFormat {
+    Raw,
+    Compressed(CSOOptions),
+}
+
+/// How sectors are assigned when rebuilding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutMode {
+    /// Directories first, then every file back to back in tree order.
+    Packed,
+    /// Entries from the source image keep their LBA when they still fit
+    /// (shrunk files are padded); grown and new entries go past the old end.
+    Preserve,
+}
+
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    pub format: ImageFormat,
+    pub layout: LayoutMode,
+    /// Recorded for new and replaced entries. Entries copied from a source
+    /// image keep their own timestamps.
+    pub timestamp: DirEntTime,
+    pub metadata: VolumeMetadata,
+    /// Order and alignment of files, for packed files and for entries the
+    /// preserved layout has to move.
+    pub sort: SortFile,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            format: ImageFormat::Raw,
+            layout: LayoutMode::Packed,
+            timestamp: DirEntTime::default(),
+            metadata: VolumeMetadata::default(),
+            sort: SortFile::default(),
+        }
+    }
+}
 
 const BLOCK_SIZE: usize = 4 * 1048 * 1024;
+
+/// Where the contents of an entry come from when the image is written.
+pub enum ISOSource {
+    Path(PathBuf),
+    /// Extent starting at this sector of the source image.
+    Image(u32),
+    Memory(Vec<u8>),
+    Stream(Box<dyn Stream>),
+    None,
+}
+impl From<OverlayData> for ISOSource {
+    fn from(data: OverlayData) -> Self {
+        match data {
+            OverlayData::File(path) => ISOSource::Path(path),
+            OverlayData::Memory(data) => ISOSource::Memory(data),
+            OverlayData::Stream(stream) => ISOSource::Stream(stream),
+        }
+    }
+}
+impl Debug for ISOSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ISOSource::Path(path) => f.debug_tuple("Path").field(path).finish(),
+            ISOSource::Image(sector) => f.debug_tuple("Image").field(sector).finish(),
+            ISOSource::Memory(data) => f.debug_tuple("Memory").field(&data.len()).finish(),
+            ISOSource::Stream(stream) => f.debug_tuple("Stream").field(&stream.size()).finish(),
+            ISOSource::None => f.write_str("None"),
+        }
+    }
+}
+
+fn copy_bytes<R: Read, W: Write>(read: &mut R, write: &mut W, size: usize) -> std::io::Result<()> {
+    let mut buff = vec![0u8; BLOCK_SIZE.min(size)];
+    let mut len = size;
+    while len > BLOCK_SIZE {
+        read.read_exact(buff.as_mut_slice())?;
+        write.write_all(&buff)?;
+        len -= BLOCK_SIZE;
+    }
+    read.read_exact(&mut buff[0..len])?;
+    write.write_all(&buff[0..len])
+}
+
+/// ISO 9660 (9.3) ordering of identifiers: file name first, then extension,
+/// each compared as if padded with spaces.
+fn compare_names(a: &str, b: &str) -> std::cmp::Ordering {
+    let split = |x: &str| {
+        let x = x.split(';').next().unwrap_or(x);
+        match x.split_once('.') {
+            Some((name, ext)) => (name.to_string(), ext.to_string()),
+            None => (x.to_string(), String::new()),
+        }
+    };
+    let pad = |x: &str, len: usize| {
+        let mut bytes = x.as_bytes().to_vec();
+        bytes.resize(len, b' ');
+        bytes
+    };
+    let (a_name, a_ext) = split(a);
+    let (b_name, b_ext) = split(b);
+    let name_len = a_name.len().max(b_name.len());
+    let ext_len = a_ext.len().max(b_ext.len());
+    pad(&a_name, name_len)
+        .cmp(&pad(&b_name, name_len))
+        .then_with(|| pad(&a_ext, ext_len).cmp(&pad(&b_ext, ext_len)))
+}
+
+/// An entry waiting for a sector, with its path from the image root.
+struct Placement<'a> {
+    path: String,
+    is_dir: bool,
+    original: Option<(u32, u32)>,
+    dirent: &'a mut DirEnt,
+}
+
+/// Every entry below `children`, parents before their children.
+fn placements<'a>(children: &'a mut [Box<ISODirent>], path: &str, ents: &mut Vec<Placement<'a>>) {
+    for child in children.iter_mut() {
+        let ISODirent {
+            is_dir,
+            dirent,
+            original,
+            children,
+            ..
+        } = &mut **child;
+        let child_path = format!("{}{}", path, dirent.name);
+        let dir_path = format!("{}/", child_path);
+        ents.push(Placement {
+            path: child_path,
+            is_dir: *is_dir,
+            original: *original,
+            dirent,
+        });
+        placements(children, &dir_path, ents);
+    }
+}
+
+/// Assigns sectors from `next` on, heaviest first and otherwise in the
+/// given order. Returns the sector after the last entry.
+fn place(ents: Vec<Placement>, next: u32, sort: &SortFile) -> u32 {
+    let mut ents = ents
+        .into_iter()
+        .map(|x| (sort.lookup(&x.path), x))
+        .collect::<Vec<_>>();
+    ents.sort_by_key(|((weight, _), _)| std::cmp::Reverse(*weight));
+    ents.into_iter().fold(next, |curr, ((_, alignment), ent)| {
+        let sector = align!(curr, alignment);
+        ent.dirent.sector = sector;
+        sector + ent.dirent.size.div_ceil(2048)
+    })
+}
+
 pub struct ISODirent {
     pub is_dir: bool,
     pub dirent: DirEnt,
-    pub path: PathBuf,
+    pub source: ISOSource,
+    /// Sector and size of the entry in the source image, if it came from one.
+    pub original: Option<(u32, u32)>,
     pub children: Vec<Box<ISODirent>>,
 }
 impl ISODirent {
+    fn from_image<S: Read + Seek>(
+        iso: &mut ISO<S>,
+        ent: &DirEnt,
+        path: &str,
+        overlay: &mut Overlay,
+        time: &DirEntTime,
+    ) -> std::io::Result<Box<Self>> {
+        let mut children = Vec::new();
+        let mut records = iso.read_dir_ents(ent.sector as u64, ent.size)?.into_iter();
+        // The directory's own `.` record, which unlike the PVD's copy of the
+        // root carries its system use bytes.
+        let dot = records.next();
+        records.next();
+        for child in records {
+            let child_path = format!("{}{}", path, &child.name);
+            let is_dir = (child.flags & 2) != 0;
+            match overlay.take(&child_path) {
+                Some(None) => (),
+                Some(Some(data)) => {
+                    if is_dir {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!("Cannot replace directory {}", child_path),
+                        ));
+                    }
+                    let size = data.size()?;
+                    children.push(Box::new(ISODirent {
+                        is_dir,
+                        dirent: DirEnt {
+                            time: Box::new(time.clone()),
+                            flags: child.flags,
+                            system_use: child.system_use.clone(),
+                            ..DirEnt::new(child.name.clone(), size, false)
+                        },
+                        source: data.into(),
+                        original: Some((child.sector, child.size)),
+                        children: Vec::new(),
+                    }));
+                }
+                None if is_dir => {
+                    children.push(Self::from_image(
+                        iso,
+                        &child,
+                        &format!("{}/", child_path),
+                        overlay,
+                        time,
+                    )?);
+                }
+                None => children.push(Box::new(ISODirent {
+                    is_dir,
+                    dirent: DirEnt {
+                        time: child.time.clone(),
+                        flags: child.flags,
+                        system_use: child.system_use.clone(),
+                        ..DirEnt::new(child.name.clone(), child.size, false)
+                    },
+                    source: ISOSource::Image(child.sector),
+                    original: Some((child.sector, child.size)),
+                    children: Vec::new(),
+                })),
+            }
+        }
+        Ok(Box::new(ISODirent {
+            is_dir: true,
+            dirent: DirEnt {
+                time: ent.time.clone(),
+                flags: ent.flags,
+                system_use: dot.map(|x| x.system_use).unwrap_or_default(),
+                ..DirEnt::new(ent.name.clone(), 0, true)
+            },
+            source: ISOSource::None,
+            original: Some((ent.sector, ent.size)),
+            children,
+        }))
+    }
+    /// Adds a file below this directory, creating any missing parents.
+    fn insert(&mut self, path: &str, data: OverlayData, time: &DirEntTime) -> std::io::Result<()> {
+        match path.split_once('/') {
+            Some((dir, rest)) => {
+                let idx = match self.children.iter().position(|x| x.dirent.name == dir) {
+                    Some(idx) => idx,
+                    None => {
+                        self.children.push(Box::new(ISODirent {
+                            is_dir: true,
+                            dirent: DirEnt {
+                                time: Box::new(time.clone()),
+                                ..DirEnt::new(dir.into(), 0, true)
+                            },
+                            source: ISOSource::None,
+                            original: None,
+                            children: Vec::new(),
+                        }));
+                        self.children.len() - 1
+                    }
+                };
+                if !self.children[idx].is_dir {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("{} is not a directory", dir),
+                    ));
+                }
+                self.children[idx].insert(rest, data, time)
+            }
+            None => {
+                let size = data.size()?;
+                self.children.push(Box::new(ISODirent {
+                    is_dir: false,
+                    dirent: DirEnt {
+                        time: Box::new(time.clone()),
+                        ..DirEnt::new(path.into(), size, false)
+                    },
+                    source: data.into(),
+                    original: None,
+                    children: Vec::new(),
+                }));
+                Ok(())
+            }
+        }
+    }
+    /// Stamps this entry and everything below it with `time`.
+    fn set_time(&mut self, time: &DirEntTime) {
+        *self.dirent.time = time.clone();
+        for child in self.children.iter_mut() {
+            child.set_time(time);
+        }
+    }
+    /// Puts every directory's children in ISO 9660 order, so records and path
+    /// tables do not depend on the order the host filesystem lists them in.
+    pub fn sort_children(&mut self) {
+        self.children
+            .sort_by(|a, b| compare_names(&a.dirent.name, &b.dirent.name));
+        self.children.iter_mut().for_each(|x| x.sort_children());
+    }
     pub fn set_xa(&mut self, xa: bool) {
         self.dirent.set_xa(xa);
         self.children.iter_mut().for_each(|x| x.set_xa(xa));
     }
+    /// Sizes each directory to the sectors its records need, starting a new
+    /// sector whenever the next record would straddle the boundary.
     pub fn update_dirsize(&mut self) {
         if self.is_dir {
-            let size = self.children.iter_mut().fold(0, |p, x| {
-                x.update_dirsize();
-                p + x.dirent.length as u32
-            });
-            // self.dirent.size = size + (48) * 2;
-            self.dirent.size = 2048;
+            let mut dot = self.dirent.clone();
+            dot.name = String::from("\x00");
+            dot.update_length();
+            let mut sectors = 1;
+            let mut used = 2 * dot.length as u32;
+            for child in self.children.iter_mut() {
+                child.update_dirsize();
+                let len = child.dirent.length as u32;
+                if used + len > 2048 {
+                    sectors += 1;
+                    used = 0;
+                }
+                used += len;
+            }
+            self.dirent.size = sectors * 2048;
         }
-        // self.dirent.set_xa(xa);
-        // self.children.iter_mut().for_each(|x| x.set_xa(xa));
     }
     pub fn allocate_dir_sectors(&mut self, next: u32) -> u32 {
         if self.is_dir {
             self.dirent.sector = next;
             self.children
                 .iter_mut()
-                .fold(next + 1, |curr, child| child.allocate_dir_sectors(curr))
+                .fold(next + self.dirent.size / 2048, |curr, child| {
+                    child.allocate_dir_sectors(curr)
+                })
         } else {
             next
         }
     }
-    pub fn allocate_file_sectors(&mut self, next: u32) -> u32 {
-        if self.is_dir {
-            self.children
-                .iter_mut()
-                .fold(next, |curr, child| child.allocate_file_sectors(curr))
-        } else {
-            self.dirent.sector = next;
-            next + (self.dirent.size + 0x7ff) / 2048
+    /// Keeps every entry that still fits at its original sector and moves the
+    /// rest (grown or new) to `next` and beyond, in `sort` order.
+    pub fn allocate_preserved(&mut self, next: &mut u32, sort: &SortFile) {
+        let ISODirent {
+            is_dir,
+            dirent,
+            original,
+            children,
+            ..
+        } = self;
+        let mut ents = vec![Placement {
+            path: String::new(),
+            is_dir: *is_dir,
+            original: *original,
+            dirent,
+        }];
+        placements(children, "", &mut ents);
+        let mut moved = Vec::new();
+        for ent in ents {
+            let sectors = ent.dirent.size.div_ceil(2048);
+            match ent.original {
+                Some((sector, size)) if sectors <= size.div_ceil(2048) => {
+                    ent.dirent.sector = sector
+                }
+                _ => moved.push(ent),
+            }
         }
+        *next = place(moved, *next, sort);
+    }
+    /// Places every file after `next`, in `sort` order.
+    pub fn allocate_file_sectors(&mut self, next: u32, sort: &SortFile) -> u32 {
+        let mut ents = Vec::new();
+        placements(&mut self.children, "", &mut ents);
+        ents.retain(|x| !x.is_dir);
+        place(ents, next, sort)
     }
     fn write_path_impl<W: Write + Seek, B: ByteOrder>(
         &self,
@@ -155,47 +512,123 @@ impl ISODirent {
         Ok((end - start) as usize)
     }
 
-    pub fn write<W: Write + Seek>(&self, write: &mut W, parent: &DirEnt) -> std::io::Result<()> {
-        let curr = write.stream_position()?;
-        write.seek(SeekFrom::Start((self.dirent.sector as u64) * 2048))?;
-        if self.is_dir {
-            let mut dot = self.dirent.clone();
-            dot.name = String::from("\x00");
-            dot.update_length();
-            dot.write(write)?;
+    /// Directory records for this directory, `.` and `..` first.
+    fn write_records<W: Write + Seek>(
+        &self,
+        write: &mut W,
+        parent: &DirEnt,
+    ) -> std::io::Result<()> {
+        let start = write.stream_position()?;
+        let mut dot = self.dirent.clone();
+        dot.name = String::from("\x00");
+        dot.update_length();
+        dot.write(write)?;
 
-            let mut dotdot = parent.clone();
-            dotdot.name = String::from("\x01");
-            dotdot.update_length();
-            dotdot.write(write)?;
+        let mut dotdot = parent.clone();
+        dotdot.name = String::from("\x01");
+        dotdot.update_length();
+        dotdot.write(write)?;
 
+        for child in self.children.iter() {
+            let used = (write.stream_position()? - start) % 2048;
+            if used + child.dirent.length as u64 > 2048 {
+                write.write_all(&vec![0u8; (2048 - used) as usize])?;
+            }
+            child.dirent.write(write)?;
+        }
+        Ok(())
+    }
+    fn write_contents<W: Write, R: Read + Seek>(
+        &self,
+        write: &mut W,
+        src: &mut R,
+    ) -> std::io::Result<()> {
+        let size = self.dirent.size as usize;
+        match &self.source {
+            ISOSource::Path(path) => copy_bytes(&mut File::open(path)?, write, size),
+            ISOSource::Image(sector) => {
+                src.seek(SeekFrom::Start((*sector as u64) * 2048))?;
+                copy_bytes(src, write, size)
+            }
+            ISOSource::Memory(data) => write.write_all(&data[0..size]),
+            ISOSource::Stream(stream) => stream.write_to(write, src),
+            ISOSource::None => Ok(()),
+        }
+    }
+    fn collect_extents<'a>(
+        &'a self,
+        parent: &DirEnt,
+        extents: &mut Vec<(u32, Extent<'a>)>,
+    ) -> std::io::Result<()> {
+        if self.is_dir {
+            let mut records = Cursor::new(Vec::new());
+            self.write_records(&mut records, parent)?;
+            extents.push((self.dirent.sector, Extent::Data(records.into_inner())));
             for child in self.children.iter() {
-                child.dirent.write(write)?;
-                child.write(write, &self.dirent)?;
+                child.collect_extents(&self.dirent, extents)?;
             }
         } else {
-            if WRITE_FILES {
-                let mut file = File::open(&self.path)?;
-                let mut buff = vec![0u8; BLOCK_SIZE];
-                let mut len = self.dirent.size as usize;
-                while len > BLOCK_SIZE {
-                    file.read_exact(buff.as_mut_slice())?;
-                    write.write_all(&buff)?;
-                    len -= BLOCK_SIZE;
-                }
-                file.read_exact(&mut buff[0..len])?;
-                write.write_all(&buff[0..len])?;
-            }
+            extents.push((self.dirent.sector, Extent::File(self)));
         }
-        write.seek(SeekFrom::Start(curr))?;
         Ok(())
     }
 }
+
+/// A run of sectors in the output image, written in LBA order.
+enum Extent<'a> {
+    Data(Vec<u8>),
+    File(&'a ISODirent),
+}
+
+fn write_zeros<W: Write>(write: &mut W, mut len: u64) -> std::io::Result<()> {
+    let zeros = [0u8; 2048];
+    while len > 0 {
+        let n = len.min(zeros.len() as u64) as usize;
+        write.write_all(&zeros[0..n])?;
+        len -= n as u64;
+    }
+    Ok(())
+}
+
+/// Streams the image front to back, zero filling any gaps, so the same
+/// sequence of bytes goes to a raw image or a compressed container.
+fn write_extents<W: Write, R: Read + Seek>(
+    write: &mut W,
+    mut extents: Vec<(u32, Extent)>,
+    total_sectors: u32,
+    src: &mut R,
+) -> std::io::Result<()> {
+    extents.sort_by_key(|x| x.0);
+    let mut pos = 0u64;
+    for (sector, extent) in extents {
+        let start = (sector as u64) * 2048;
+        if start < pos {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Extent at sector {} overlaps the previous one", sector),
+            ));
+        }
+        write_zeros(write, start - pos)?;
+        let len = match extent {
+            Extent::Data(data) => {
+                write.write_all(&data)?;
+                data.len() as u64
+            }
+            Extent::File(ent) => {
+                ent.write_contents(write, src)?;
+                ent.dirent.size as u64
+            }
+        };
+        pos = start + len;
+    }
+    write_zeros(write, ((total_sectors as u64) * 2048).saturating_sub(pos))
+}
+
 impl Debug for ISODirent {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ISODirent")
             // .field("is_dir", &self.is_dir)
-            .field("path", &self.path)
+            .field("source", &self.source)
             .field("dirent", &self.dirent)
             .field("children", &self.children)
             .finish()
@@ -224,7 +657,8 @@ impl TryFrom<PathBuf> for Box<ISODirent> {
         Ok(Self::new(ISODirent {
             is_dir,
             dirent,
-            path,
+            source: ISOSource::Path(path),
+            original: None,
             children,
         }))
     }
@@ -237,139 +671,262 @@ impl TryFrom<PathBuf> for Box<ISODirent> {
 //     }
 // }
 
-impl ISO {
-    pub fn new(fp: File) -> Self {
-        Self { fp: fp }
+impl ISO<File> {
+    /// Opens an existing image, which may be a plain ISO or a CSO/ZSO.
+    pub fn from_file(fp: File) -> std::io::Result<Self> {
+        Self::from_reader(fp)
     }
+}
 
-    pub fn build_from_dir(&mut self, mut pvd: Box<PVD>, path: PathBuf) -> std::io::Result<()> {
-        let mut sector = [0x20u8; 2048];
-
-        self.write_sector(14, &sector)?;
-        self.write_sector(15, &sector)?;
-        sector.fill(0);
-        sector[0] = 0xff;
-        sector[1..6].copy_from_slice("CD001".as_bytes());
-        sector[6] = 1;
-        self.write_sector(17, &sector)?;
-
-        let mut root: Box<ISODirent> = (path).try_into()?;
-        root.set_xa(true);
-        root.update_dirsize();
-        let file_sector = root.allocate_dir_sectors(22);
-        let last_sector = root.allocate_file_sectors(file_sector);
-        let size = root.write_path_table::<_, LittleEndian>(&mut self.fp, pvd.l_sector)?;
-        pvd.path_table_size = size as u32;
-        root.write_path_table::<_, LittleEndian>(&mut self.fp, pvd.l_sector_opt)?;
-        root.write_path_table::<_, BigEndian>(&mut self.fp, pvd.m_sector)?;
-        root.write_path_table::<_, BigEndian>(&mut self.fp, pvd.m_sector_opt)?;
-        pvd.volume_space_size = align!(last_sector, 16);
-        pvd.root_ent = Box::new(root.dirent.clone());
-        pvd.root_ent.set_xa(false);
-        pvd.root_ent.name = String::from("\x00");
-        pvd.root_ent.update_length();
-
-        self.fp.seek(SeekFrom::Start(2048 * 16))?;
-        pvd.write(&mut self.fp)?;
-        self.fp.write_u8(0)?;
-        write_string_pad(&mut self.fp, "NPJH-50581|4185637DD632EE5C|0001", 0x8d)?;
-        write_string_pad(&mut self.fp, "CD-XA001", 0x173)?;
-
-        root.write(&mut self.fp, &root.dirent)?;
-        if WRITE_FILES {
-            if pvd.volume_space_size > last_sector {
-                sector.fill(0);
-                self.write_sector((pvd.volume_space_size - 1) as u64, &sector)?;
-            }
+impl<S: Read + Seek> ISO<S> {
+    /// Wraps storage as a plain image, e.g. an empty file or `Cursor` to build into.
+    pub fn new(fp: S) -> Self {
+        Self {
+            fp: BlockDevice::Raw(fp),
         }
-
-        Ok(())
+    }
+    /// Opens an existing image from any seekable stream, decoding CSO/ZSO on the fly.
+    pub fn from_reader(fp: S) -> std::io::Result<Self> {
+        Ok(Self {
+            fp: BlockDevice::open(fp)?,
+        })
+    }
+    /// Size of the image in bytes; the decoded size for CSO/ZSO.
+    pub fn image_size(&mut self) -> std::io::Result<u64> {
+        self.fp.seek(SeekFrom::End(0))
     }
     pub fn read_sector(&mut self, sector: u64, buff: &mut [u8; 2048]) -> std::io::Result<()> {
         self.fp.seek(std::io::SeekFrom::Start(sector * 2048))?;
         self.fp.read_exact(buff)
     }
-    pub fn write_sector(&mut self, sector: u64, buff: &[u8; 2048]) -> std::io::Result<()> {
-        self.fp.seek(std::io::SeekFrom::Start(sector * 2048))?;
-        self.fp.write_all(buff)
-    }
 
-    fn extract_dir(&mut self, path: &Path, ent: &Box<DirEnt>) -> std::io::Result<()> {
-        let children = self.read_dir_ents(ent.sector as u64)?;
+    fn extract_dir(
+        &mut self,
+        path: &Path,
+        image_path: &str,
+        ent: &Box<DirEnt>,
+        options: &ExtractOptions,
+    ) -> std::io::Result<()> {
+        let children = self.read_dir_ents(ent.sector as u64, ent.size)?;
         for child in children.into_iter().skip(2) {
+            let child_path = format!("{}{}", image_path, child.name);
+            if ExtractOptions::matches(&options.exclude, &child_path) {
+                continue;
+            }
             if (child.flags & 2) == 0 {
                 //file
-                self.extract_file(path, &child)?;
+                if options.include.is_empty()
+                    || ExtractOptions::matches(&options.include, &child_path)
+                {
+                    self.extract_file(path, &child, options)?;
+                }
             } else {
                 let mut new_path = path.to_path_buf();
                 new_path.push(&child.name);
-                std::fs::create_dir_all(&new_path)?;
-                self.extract_dir(&new_path, &child)?;
+                if options.include.is_empty()
+                    || ExtractOptions::matches(&options.include, &child_path)
+                {
+                    std::fs::create_dir_all(&new_path)?;
+                }
+                self.extract_dir(&new_path, &format!("{}/", child_path), &child, options)?;
             }
         }
         Ok(())
     }
-    fn extract_file(&mut self, dir: &Path, ent: &Box<DirEnt>) -> std::io::Result<()> {
+    /// Looks up an entry by its path from the image root, e.g. `PSP_GAME/SYSDIR/EBOOT.BIN`.
+    /// Names are matched case-insensitively; an empty path is the root directory.
+    pub fn stat(&mut self, path: &str) -> std::io::Result<Box<DirEnt>> {
+        let mut ent = self.get_pvd()?.root_ent;
+        for name in path.split('/').filter(|x| !x.is_empty()) {
+            if (ent.flags & 2) == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("{} not found", path),
+                ));
+            }
+            ent = self
+                .read_dir_ents(ent.sector as u64, ent.size)?
+                .into_iter()
+                .skip(2)
+                .find(|x| x.name.eq_ignore_ascii_case(name))
+                .ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} not found", path))
+                })?;
+        }
+        Ok(ent)
+    }
+    /// Lists a directory without its `.` and `..` records.
+    pub fn read_dir(&mut self, path: &str) -> std::io::Result<Vec<DirEnt>> {
+        let ent = self.stat(path)?;
+        if (ent.flags & 2) == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", path),
+            ));
+        }
+        let children = self.read_dir_ents(ent.sector as u64, ent.size)?;
+        Ok(children.into_iter().skip(2).map(|x| *x).collect())
+    }
+    /// Every file and directory in the image with its full path, breadth first.
+    pub fn walk(&mut self) -> std::io::Result<Vec<(String, Box<DirEnt>)>> {
+        let mut ents = Vec::new();
+        let mut q: VecDeque<(String, Box<DirEnt>)> = VecDeque::new();
+        q.push_back(("".into(), self.get_pvd()?.root_ent));
+        while let Some((path, ent)) = q.pop_front() {
+            let children = self.read_dir_ents(ent.sector as u64, ent.size)?;
+            for child in children.into_iter().skip(2) {
+                let child_path = format!("{}{}", path, child.name);
+                if (child.flags & 2) != 0 {
+                    q.push_back((format!("{}/", child_path), child.clone()));
+                }
+                ents.push((child_path, child));
+            }
+        }
+        Ok(ents)
+    }
+    /// The whole image as one stream, decoded if it is a CSO/ZSO.
+    pub fn open_image(&mut self) -> std::io::Result<ISOFile<'_, S>> {
+        let size = self.image_size()?;
+        self.fp.seek(SeekFrom::Start(0))?;
+        Ok(ISOFile {
+            fp: &mut self.fp,
+            start: 0,
+            size,
+            pos: 0,
+        })
+    }
+    /// Opens a file by path for random access without extracting it.
+    pub fn open(&mut self, path: &str) -> std::io::Result<ISOFile<'_, S>> {
+        let ent = self.stat(path)?;
+        if (ent.flags & 2) != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is a directory", path),
+            ));
+        }
+        self.open_file(&ent)
+    }
+    pub fn open_file(&mut self, ent: &DirEnt) -> std::io::Result<ISOFile<'_, S>> {
+        let start = (ent.sector as u64) * 2048;
+        self.fp.seek(SeekFrom::Start(start))?;
+        Ok(ISOFile {
+            fp: &mut self.fp,
+            start,
+            size: ent.size as u64,
+            pos: 0,
+        })
+    }
+    pub fn read_file(&mut self, path: &str) -> std::io::Result<Vec<u8>> {
+        let mut file = self.open(path)?;
+        let mut data = Vec::with_capacity(file.size as usize);
+        file.read_to_end(&mut data)?;
+        Ok(data)
+    }
+    fn extract_file(
+        &mut self,
+        dir: &Path,
+        ent: &Box<DirEnt>,
+        options: &ExtractOptions,
+    ) -> std::io::Result<()> {
         let mut path = dir.to_path_buf();
         path.push(&ent.name);
         println!("Extracting {}", &path.to_str().unwrap());
+        std::fs::create_dir_all(dir)?;
         let mut out = OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
             .open(&path)?;
         let sector = ent.sector as u64;
-        let mut buff = vec![0u8; BLOCK_SIZE];
 
         self.fp.seek(SeekFrom::Start(sector * 2048))?;
-        let mut len = ent.size as usize;
-        while len > BLOCK_SIZE {
-            self.fp.read_exact(buff.as_mut_slice())?;
-            out.write_all(&buff)?;
-            len -= BLOCK_SIZE;
-        }
-        self.fp.read_exact(&mut buff[0..len])?;
-        out.write_all(&buff[0..len])?;
+        copy_bytes(&mut self.fp, &mut out, ent.size as usize)?;
 
-        out.flush()
+        out.flush()?;
+        if options.preserve_times {
+            if let Some(time) = ent.time.to_chrono() {
+                out.set_modified(time.into())?;
+            }
+        }
+        Ok(())
     }
-    pub fn extract(&mut self, folder: &Path) -> std::io::Result<()> {
+    /// Extracts the files picked by `options` below `folder`, keeping the
+    /// image's directory structure.
+    pub fn extract_with(&mut self, folder: &Path, options: &ExtractOptions) -> std::io::Result<()> {
         let pvd = self.get_pvd()?;
-        self.extract_dir(folder, &pvd.root_ent)
+        self.extract_dir(folder, "", &pvd.root_ent, options)
     }
-    pub fn read_dir_ents(&mut self, sector: u64) -> std::io::Result<Vec<Box<DirEnt>>> {
-        let mut ents = Vec::new();
+    /// Reads every record of a directory extent. Records never cross a sector
+    /// boundary, so a zero length byte means the rest of that sector is padding.
+    pub fn read_dir_ents(&mut self, sector: u64, size: u32) -> std::io::Result<Vec<Box<DirEnt>>> {
+        Ok(self
+            .read_dir_records(sector, size)?
+            .into_iter()
+            .map(|x| x.1)
+            .collect())
+    }
+    /// Same as `read_dir_ents`, paired with each record's byte offset in the image.
+    fn read_dir_records(
+        &mut self,
+        sector: u64,
+        size: u32,
+    ) -> std::io::Result<Vec<(u64, Box<DirEnt>)>> {
+        let mut data = vec![0u8; align!(size.max(1) as usize, 2048)];
         self.fp.seek(SeekFrom::Start(sector * 2048))?;
-        loop {
-            if self.fp.read_u8()? == 0 {
-                break;
+        self.fp.read_exact(&mut data)?;
+
+        let mut ents = Vec::new();
+        let mut read = Cursor::new(&data);
+        let mut pos = 0;
+        while pos < data.len() {
+            if data[pos] == 0 {
+                pos = align!(pos + 1, 2048);
+                continue;
             }
-            self.fp.seek(SeekFrom::Current(-1))?;
-            ents.push(DirEnt::read(&mut self.fp)?);
+            read.set_position(pos as u64);
+            let ent = DirEnt::read(&mut read)?;
+            ents.push((sector * 2048 + pos as u64, ent));
+            pos += ents.last().unwrap().1.length as usize;
         }
 
         Ok(ents)
     }
-    // pub fn print_dir(&mut self) -> std::io::Result<()> {
-    //     let pvd = self.get_pvd()?;
-
-    //     let mut q: VecDeque<(String, Box<DirEnt>)> = VecDeque::new();
-    //     q.push_back(("".into(), pvd.root_ent));
-    //     while !q.is_empty() {
-    //         let ent = q.pop_front().unwrap();
-    //         if ent.1.flags & 2 != 0 {
-    //             let children = self.read_dir_ents(ent.1.sector as u64)?;
-    //             children
-    //                 .into_iter()
-    //                 .skip(2)
-    //                 .map(|x| (format!("{}{}/", ent.0, ent.1.name), x))
-    //                 .for_each(|x| q.push_back(x))
-    //         } else {
-    //             println!("{}{}", ent.0, ent.1.name);
-    //         }
-    //     }
-    //     Ok(())
-    // }
+    /// Reads the system area and the volume descriptor set. Supplementary
+    /// descriptors (Joliet) are dropped since their tree is not rebuilt.
+    pub fn get_volume_header(&mut self) -> std::io::Result<VolumeHeader> {
+        let mut system_area = vec![0u8; 16 * 2048];
+        self.fp.seek(SeekFrom::Start(0))?;
+        self.fp.read_exact(&mut system_area)?;
+
+        let pvd = self.get_pvd()?;
+        let mut descriptors = Vec::new();
+        for sector in 17..32 {
+            let mut buff = [0u8; 2048];
+            self.read_sector(sector, &mut buff)?;
+            if &buff[1..6] != b"CD001" {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Sector {} is not a volume descriptor", sector),
+                ));
+            }
+            match buff[0] {
+                2 => (),
+                0xff => {
+                    descriptors.push(buff.to_vec());
+                    return Ok(VolumeHeader {
+                        system_area,
+                        pvd,
+                        descriptors,
+                    });
+                }
+                _ => descriptors.push(buff.to_vec()),
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Volume descriptor set has no terminator",
+        ))
+    }
     pub fn get_pvd(&mut self) -> std::io::Result<Box<PVD>> {
         // let pvd: PVD;
         // let mut sector: [u8; 2048] = [0; 2048];
@@ -379,3 +936,620 @@ impl ISO {
         PVD::read(&mut self.fp)
     }
 }
+
+impl<S: Read + Write + Seek> ISO<S> {
+    pub fn build_from_dir(
+        &mut self,
+        header: VolumeHeader,
+        path: PathBuf,
+        options: &BuildOptions,
+    ) -> std::io::Result<Checksums> {
+        let mut root: Box<ISODirent> = (path).try_into()?;
+        root.set_time(&options.timestamp);
+        self.build(header, *root, &mut std::io::empty(), options)
+    }
+    /// Rebuilds `src` with the changes in `overlay` applied. Unchanged files
+    /// are copied straight from the source image. Returns the checksums of
+    /// the uncompressed image, hashed as it was written.
+    pub fn build_from_image<T: Read + Seek>(
+        &mut self,
+        src: &mut ISO<T>,
+        mut overlay: Overlay,
+        options: &BuildOptions,
+    ) -> std::io::Result<Checksums> {
+        let volume = src.get_volume_header()?;
+        let time = &options.timestamp;
+        let mut root = ISODirent::from_image(src, &volume.pvd.root_ent, "", &mut overlay, time)?;
+        for (path, data) in overlay.into_additions() {
+            root.insert(&path, data, time)?;
+        }
+        self.build(volume, *root, &mut src.fp, options)
+    }
+    fn build<R: Read + Seek>(
+        &mut self,
+        volume: VolumeHeader,
+        mut root: ISODirent,
+        src: &mut R,
+        options: &BuildOptions,
+    ) -> std::io::Result<Checksums> {
+        let mut pvd = volume.pvd;
+        options.metadata.apply(&mut pvd);
+        let mut header = Cursor::new(Vec::new());
+        header.write_all(&volume.system_area)?;
+        header.seek(SeekFrom::Start(17 * 2048))?;
+        for descriptor in volume.descriptors.iter() {
+            header.write_all(descriptor)?;
+        }
+
+        root.sort_children();
+        root.set_xa(true);
+        root.update_dirsize();
+
+        // Directories start after the last path table, wherever the source
+        // descriptor set put them.
+        let table_sectors = root
+            .write_path_table::<_, LittleEndian>(&mut Cursor::new(Vec::new()), 0)?
+            .div_ceil(2048);
+        if table_sectors > (pvd.path_table_size as usize).div_ceil(2048) {
+            if let LayoutMode::Preserve = options.layout {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Path table no longer fits in its original sectors, use the packed layout",
+                ));
+            }
+            // Everything after the descriptors is placed anew, so the grown
+            // tables go back to back right after them. Optional tables the
+            // source did not have stay absent.
+            let mut next = 17 + volume.descriptors.len() as u32;
+            for sector in [
+                &mut pvd.l_sector,
+                &mut pvd.l_sector_opt,
+                &mut pvd.m_sector,
+                &mut pvd.m_sector_opt,
+            ] {
+                if *sector != 0 {
+                    *sector = next;
+                    next += table_sectors as u32;
+                }
+            }
+        }
+        let first_sector = [
+            pvd.l_sector,
+            pvd.l_sector_opt,
+            pvd.m_sector,
+            pvd.m_sector_opt,
+        ]
+        .iter()
+        .map(|x| x + table_sectors as u32)
+        .fold(22, u32::max);
+        let volume_space_size = match options.layout {
+            LayoutMode::Packed => {
+                let file_sector = root.allocate_dir_sectors(first_sector);
+                align!(root.allocate_file_sectors(file_sector, &options.sort), 16)
+            }
+            LayoutMode::Preserve => {
+                let end = pvd.volume_space_size.max(first_sector);
+                let mut next = end;
+                root.allocate_preserved(&mut next, &options.sort);
+                if next == end {
+                    end
+                } else {
+                    align!(next, 16)
+                }
+            }
+        };
+        let size = root.write_path_table::<_, LittleEndian>(&mut header, pvd.l_sector)?;
+        pvd.path_table_size = size as u32;
+        if pvd.l_sector_opt != 0 {
+            root.write_path_table::<_, LittleEndian>(&mut header, pvd.l_sector_opt)?;
+        }
+        root.write_path_table::<_, BigEndian>(&mut header, pvd.m_sector)?;
+        if pvd.m_sector_opt != 0 {
+            root.write_path_table::<_, BigEndian>(&mut header, pvd.m_sector_opt)?;
+        }
+        pvd.volume_space_size = volume_space_size;
+        pvd.root_ent = Box::new(root.dirent.clone());
+        pvd.root_ent.set_xa(false);
+        pvd.root_ent.name = String::from("\x00");
+        pvd.root_ent.update_length();
+
+        header.seek(SeekFrom::Start(2048 * 16))?;
+        pvd.write(&mut header)?;
+
+        let mut extents = vec![(0, Extent::Data(header.into_inner()))];
+        root.collect_extents(&root.dirent, &mut extents)?;
+
+        self.fp.seek(SeekFrom::Start(0))?;
+        match &options.format {
+            ImageFormat::Raw => {
+                let mut out =
+                    HashingWriter::new(BufWriter::with_capacity(BLOCK_SIZE, &mut self.fp));
+                write_extents(&mut out, extents, pvd.volume_space_size, src)?;
+                let (mut out, checksums) = out.finish();
+                out.flush()?;
+                Ok(checksums)
+            }
+            ImageFormat::Compressed(cso) => {
+                let total = (pvd.volume_space_size as u64) * 2048;
+                let out = CSOWriter::new(BufWriter::new(&mut self.fp), cso, total)?;
+                let mut out = HashingWriter::new(out);
+                write_extents(&mut out, extents, pvd.volume_space_size, src)?;
+                let (out, checksums) = out.finish();
+                out.finish()?.flush()?;
+                Ok(checksums)
+            }
+        }
+    }
+    /// Overwrites a single file without rebuilding the image. The data goes
+    /// into the file's existing sectors when it fits; otherwise it is appended
+    /// after the end of the volume if `relocate` is set, and an error is
+    /// returned if not. Only the record's location and size are patched.
+    pub fn replace_file(&mut self, path: &str, data: &[u8], relocate: bool) -> std::io::Result<()> {
+        let path = path.trim_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let dir = self.stat(parent)?;
+        let (pos, ent) = self
+            .read_dir_records(dir.sector as u64, dir.size)?
+            .into_iter()
+            .skip(2)
+            .find(|x| x.1.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} not found", path))
+            })?;
+        if (ent.flags & 2) != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is a directory", path),
+            ));
+        }
+        let size: u32 = data.len().try_into().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is too large", path),
+            )
+        })?;
+        let len = align!(data.len(), 2048);
+
+        let sector = if len <= align!(ent.size as usize, 2048) {
+            ent.sector
+        } else if relocate {
+            let pvd = self.get_pvd()?;
+            let sector = pvd.volume_space_size;
+            self.fp.seek(SeekFrom::Start(16 * 2048 + 80))?;
+            write_u32bi(&mut self.fp, sector + (len / 2048) as u32)?;
+            sector
+        } else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} needs {} sectors but only has {}",
+                    path,
+                    len / 2048,
+                    align!(ent.size as usize, 2048) / 2048
+                ),
+            ));
+        };
+        self.fp.seek(SeekFrom::Start(sector as u64 * 2048))?;
+        self.fp.write_all(data)?;
+        write_zeros(&mut self.fp, (len - data.len()) as u64)?;
+
+        self.fp.seek(SeekFrom::Start(pos + 2))?;
+        write_u32bi(&mut self.fp, sector)?;
+        write_u32bi(&mut self.fp, size)?;
+        self.fp.flush()
+    }
+}
+
+/// Read-only view of a single file's extent inside an image.
+pub struct ISOFile<'a, S = File> {
+    fp: &'a mut BlockDevice<S>,
+    start: u64,
+    size: u64,
+    pos: u64,
+}
+
+impl<S: Read + Seek> Read for ISOFile<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = (buf.len() as u64).min(self.size.saturating_sub(self.pos)) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+        self.fp.seek(SeekFrom::Start(self.start + self.pos))?;
+        let read = self.fp.read(&mut buf[0..len])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<S: Read + Seek> Seek for ISOFile<'_, S> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(off) => off as i64,
+            SeekFrom::End(off) => self.size as i64 + off,
+            SeekFrom::Current(off) => self.pos as i64 + off,
+        };
+        if pos < 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before start of file",
+            ));
+        }
+        self.pos = pos as u64;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::lib::fsck::{self, Severity};
+
+    /// An empty directory under the system temp dir, for trees to build from.
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("patcher-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A blank system area and a PVD with the path tables at sectors 18-21.
+    pub(crate) fn volume_header() -> VolumeHeader {
+        let time = || Box::new(PVDTime::Unspecified([0; 17]));
+        let mut terminator = vec![0u8; 2048];
+        terminator[0] = 0xff;
+        terminator[1..6].copy_from_slice(b"CD001");
+        terminator[6] = 1;
+        VolumeHeader {
+            system_area: vec![0; 16 * 2048],
+            pvd: Box::new(PVD {
+                pvd_type: 1,
+                id: "CD001".into(),
+                version: 1,
+                system_id: "PSP GAME".into(),
+                volume_id: "UMD DATA".into(),
+                volume_space_size: 0,
+                volume_set_size: 1,
+                volume_seq_num: 1,
+                block_size: 2048,
+                path_table_size: 0,
+                l_sector: 18,
+                l_sector_opt: 19,
+                m_sector: 20,
+                m_sector_opt: 21,
+                root_ent: Box::new(DirEnt::new("\x00".into(), 2048, true)),
+                set_id: String::new(),
+                pub_id: String::new(),
+                prep_id: String::new(),
+                app_id: "PSP GAME".into(),
+                copyright_file: String::new(),
+                abstract_file: String::new(),
+                biblio_file: String::new(),
+                created: time(),
+                modified: time(),
+                expired: time(),
+                effective: time(),
+                file_version: 1,
+                application_use: vec![0x20; 512],
+            }),
+            descriptors: vec![terminator],
+        }
+    }
+
+    /// Builds an image of `dir` in memory.
+    pub(crate) fn build_image(dir: &Path, options: &BuildOptions) -> Vec<u8> {
+        let mut image = Cursor::new(Vec::new());
+        ISO::new(&mut image)
+            .build_from_dir(volume_header(), dir.to_path_buf(), options)
+            .unwrap();
+        image.into_inner()
+    }
+
+    pub(crate) fn open_image(image: Vec<u8>) -> ISO<Cursor<Vec<u8>>> {
+        ISO::from_reader(Cursor::new(image)).unwrap()
+    }
+
+    pub(crate) fn assert_no_errors<S: Read + Seek>(iso: &mut ISO<S>) {
+        let findings = fsck::check(iso).unwrap();
+        let errors = findings
+            .iter()
+            .filter(|x| x.severity == Severity::Error)
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        assert!(errors.is_empty(), "{:#?}", errors);
+    }
+
+    #[test]
+    fn directory_over_several_sectors() {
+        let dir = temp_dir("bigdir");
+        std::fs::create_dir_all(dir.join("BIG")).unwrap();
+        for i in 0..150 {
+            let name = format!("BIG/FILE_WITH_A_LONG_NAME_{:03}.BIN", i);
+            std::fs::write(dir.join(name), format!("file {}", i)).unwrap();
+        }
+        std::fs::write(dir.join("LAST.BIN"), b"last").unwrap();
+        let mut iso = open_image(build_image(&dir, &BuildOptions::default()));
+
+        let big = iso.stat("BIG").unwrap();
+        assert!(big.size > 4 * 2048, "{}", big.size);
+        assert_eq!(big.size % 2048, 0);
+        let names = iso
+            .read_dir("BIG")
+            .unwrap()
+            .into_iter()
+            .map(|x| x.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names.len(), 150);
+        assert_eq!(names[149], "FILE_WITH_A_LONG_NAME_149.BIN");
+        for i in [0, 70, 149] {
+            let path = format!("BIG/FILE_WITH_A_LONG_NAME_{:03}.BIN", i);
+            assert_eq!(
+                iso.read_file(&path).unwrap(),
+                format!("file {}", i).as_bytes()
+            );
+        }
+        assert_eq!(iso.read_file("LAST.BIN").unwrap(), b"last");
+        assert_no_errors(&mut iso);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn path_api() {
+        let dir = temp_dir("paths");
+        std::fs::create_dir_all(dir.join("PSP_GAME/SYSDIR")).unwrap();
+        let eboot = (0..10000u32).map(|x| x as u8).collect::<Vec<_>>();
+        std::fs::write(dir.join("PSP_GAME/SYSDIR/EBOOT.BIN"), &eboot).unwrap();
+        std::fs::write(dir.join("PSP_GAME/PARAM.SFO"), b"param").unwrap();
+        let mut iso = open_image(build_image(&dir, &BuildOptions::default()));
+
+        let ent = iso.stat("/psp_game/sysdir/eboot.bin").unwrap();
+        assert_eq!((ent.name.as_str(), ent.size), ("EBOOT.BIN", 10000));
+        assert_eq!(
+            iso.stat("PSP_GAME/NOPE").unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        let names = |x: Vec<DirEnt>| x.into_iter().map(|x| x.name).collect::<Vec<_>>();
+        assert_eq!(names(iso.read_dir("").unwrap()), ["PSP_GAME"]);
+        assert_eq!(
+            names(iso.read_dir("PSP_GAME").unwrap()),
+            ["PARAM.SFO", "SYSDIR"]
+        );
+        assert!(iso.read_dir("PSP_GAME/PARAM.SFO").is_err());
+        assert!(iso.open("PSP_GAME").is_err());
+
+        let mut file = iso.open("PSP_GAME/SYSDIR/EBOOT.BIN").unwrap();
+        let mut buff = [0u8; 100];
+        file.seek(SeekFrom::Start(9950)).unwrap();
+        assert_eq!(file.read(&mut buff).unwrap(), 50);
+        assert_eq!(&buff[..50], &eboot[9950..]);
+        file.seek(SeekFrom::End(-10000)).unwrap();
+        file.read_exact(&mut buff).unwrap();
+        assert_eq!(&buff[..], &eboot[..100]);
+
+        let paths = iso
+            .walk()
+            .unwrap()
+            .into_iter()
+            .map(|x| x.0)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                "PSP_GAME",
+                "PSP_GAME/PARAM.SFO",
+                "PSP_GAME/SYSDIR",
+                "PSP_GAME/SYSDIR/EBOOT.BIN"
+            ]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn sample_tree(name: &str) -> PathBuf {
+        let dir = temp_dir(name);
+        std::fs::create_dir_all(dir.join("PSP_GAME/SYSDIR")).unwrap();
+        std::fs::create_dir_all(dir.join("PSP_GAME/USRDIR/PACK")).unwrap();
+        std::fs::write(dir.join("PSP_GAME/SYSDIR/EBOOT.BIN"), vec![1; 5000]).unwrap();
+        std::fs::write(dir.join("PSP_GAME/USRDIR/PACK/ALL.CPK"), vec![2; 70000]).unwrap();
+        std::fs::write(dir.join("UMD_DATA.BIN"), b"ULJM-06081|").unwrap();
+        dir
+    }
+
+    #[test]
+    fn extract_from_memory() {
+        let dir = sample_tree("extract_src");
+        let mut iso = open_image(build_image(&dir, &BuildOptions::default()));
+        assert_no_errors(&mut iso);
+
+        let out = temp_dir("extract_out");
+        let options = ExtractOptions {
+            include: vec![Pattern::new("psp_game/usrdir").unwrap()],
+            ..Default::default()
+        };
+        iso.extract_with(&out, &options).unwrap();
+        let cpk = std::fs::read(out.join("PSP_GAME/USRDIR/PACK/ALL.CPK")).unwrap();
+        assert_eq!(cpk, vec![2; 70000]);
+        assert!(!out.join("PSP_GAME/SYSDIR").exists());
+        assert!(!out.join("UMD_DATA.BIN").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+        std::fs::remove_dir_all(out).unwrap();
+    }
+
+    #[test]
+    fn compressed_image() {
+        let dir = sample_tree("compressed");
+        let raw = build_image(&dir, &BuildOptions::default());
+        let options = BuildOptions {
+            format: ImageFormat::Compressed(CSOOptions::default()),
+            ..Default::default()
+        };
+        let image = build_image(&dir, &options);
+        assert!(image.len() < raw.len());
+        let mut iso = open_image(image);
+        assert_eq!(iso.image_size().unwrap(), raw.len() as u64);
+        let mut decoded = Vec::new();
+        iso.open_image().unwrap().read_to_end(&mut decoded).unwrap();
+        assert!(decoded == raw);
+        assert_eq!(
+            iso.read_file("PSP_GAME/SYSDIR/EBOOT.BIN").unwrap(),
+            vec![1; 5000]
+        );
+        assert_no_errors(&mut iso);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replace_in_place() {
+        let dir = sample_tree("replace");
+        let mut image = Cursor::new(build_image(&dir, &BuildOptions::default()));
+        let mut iso = ISO::new(&mut image);
+        let eboot = iso.stat("PSP_GAME/SYSDIR/EBOOT.BIN").unwrap();
+        let end = iso.get_pvd().unwrap().volume_space_size;
+
+        iso.replace_file("PSP_GAME/SYSDIR/EBOOT.BIN", &[3; 6000], false)
+            .unwrap();
+        let e = iso
+            .replace_file("PSP_GAME/SYSDIR/EBOOT.BIN", &[4; 7000], false)
+            .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        iso.replace_file("UMD_DATA.BIN", &[5; 5000], true).unwrap();
+
+        let mut iso = open_image(image.into_inner());
+        let ent = iso.stat("PSP_GAME/SYSDIR/EBOOT.BIN").unwrap();
+        assert_eq!((ent.sector, ent.size), (eboot.sector, 6000));
+        assert_eq!(
+            iso.read_file("PSP_GAME/SYSDIR/EBOOT.BIN").unwrap(),
+            vec![3; 6000]
+        );
+        assert_eq!(iso.stat("UMD_DATA.BIN").unwrap().sector, end);
+        assert_eq!(iso.get_pvd().unwrap().volume_space_size, end + 3);
+        assert_eq!(iso.read_file("UMD_DATA.BIN").unwrap(), vec![5; 5000]);
+        assert_no_errors(&mut iso);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn preserve_layout() {
+        let dir = temp_dir("preserve");
+        std::fs::create_dir_all(dir.join("DATA")).unwrap();
+        std::fs::write(dir.join("DATA/KEEP.BIN"), vec![1; 5000]).unwrap();
+        std::fs::write(dir.join("DATA/SAME.BIN"), vec![2; 3000]).unwrap();
+        std::fs::write(dir.join("DATA/GROW.BIN"), vec![3; 2048]).unwrap();
+        std::fs::write(dir.join("GONE.BIN"), vec![4; 100]).unwrap();
+        let mut src = open_image(build_image(&dir, &BuildOptions::default()));
+        let end = src.get_pvd().unwrap().volume_space_size;
+
+        let mut overlay = Overlay::new();
+        overlay.replace("DATA/SAME.BIN", OverlayData::Memory(vec![5; 4096]));
+        overlay.replace("DATA/GROW.BIN", OverlayData::Memory(vec![6; 2049]));
+        overlay.replace("DATA/NEW.BIN", OverlayData::Memory(vec![7; 10]));
+        overlay.delete("GONE.BIN");
+        let options = BuildOptions {
+            layout: LayoutMode::Preserve,
+            ..Default::default()
+        };
+        let mut image = Cursor::new(Vec::new());
+        ISO::new(&mut image)
+            .build_from_image(&mut src, overlay, &options)
+            .unwrap();
+        let mut iso = open_image(image.into_inner());
+
+        for path in ["DATA", "DATA/KEEP.BIN", "DATA/SAME.BIN"] {
+            let before = src.stat(path).unwrap().sector;
+            assert_eq!(iso.stat(path).unwrap().sector, before, "{}", path);
+        }
+        for path in ["DATA/GROW.BIN", "DATA/NEW.BIN"] {
+            assert!(iso.stat(path).unwrap().sector >= end, "{}", path);
+        }
+        assert!(iso.stat("GONE.BIN").is_err());
+        assert_eq!(iso.read_file("DATA/KEEP.BIN").unwrap(), vec![1; 5000]);
+        assert_eq!(iso.read_file("DATA/SAME.BIN").unwrap(), vec![5; 4096]);
+        assert_eq!(iso.read_file("DATA/GROW.BIN").unwrap(), vec![6; 2049]);
+        assert_eq!(iso.read_file("DATA/NEW.BIN").unwrap(), vec![7; 10]);
+        assert_no_errors(&mut iso);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn grown_path_tables_are_moved() {
+        let dir = temp_dir("pathtables");
+        std::fs::write(dir.join("FIRST.BIN"), b"first").unwrap();
+        let mut src = open_image(build_image(&dir, &BuildOptions::default()));
+        let before = src.get_pvd().unwrap();
+        assert!(before.path_table_size <= 2048);
+        let overlay = || {
+            let mut overlay = Overlay::new();
+            for i in 0..150 {
+                let path = format!("DIRECTORY_NUMBER_{:03}/FILE.BIN", i);
+                overlay.replace(&path, OverlayData::Memory(path.clone().into_bytes()));
+            }
+            overlay
+        };
+
+        let mut image = Cursor::new(Vec::new());
+        let options = BuildOptions::default();
+        ISO::new(&mut image)
+            .build_from_image(&mut src, overlay(), &options)
+            .unwrap();
+        let mut iso = open_image(image.into_inner());
+        let pvd = iso.get_pvd().unwrap();
+        let sectors = pvd.path_table_size.div_ceil(2048);
+        assert!(sectors > 1);
+        assert_eq!(pvd.l_sector_opt, pvd.l_sector + sectors);
+        assert_eq!(pvd.m_sector, pvd.l_sector_opt + sectors);
+        assert_eq!(pvd.m_sector_opt, pvd.m_sector + sectors);
+        assert!(iso.stat("").unwrap().sector >= pvd.m_sector_opt + sectors);
+        assert_eq!(iso.read_file("FIRST.BIN").unwrap(), b"first");
+        let path = "DIRECTORY_NUMBER_149/FILE.BIN";
+        assert_eq!(iso.read_file(path).unwrap(), path.as_bytes());
+        assert_no_errors(&mut iso);
+
+        let options = BuildOptions {
+            layout: LayoutMode::Preserve,
+            ..Default::default()
+        };
+        let result =
+            ISO::new(&mut Cursor::new(Vec::new())).build_from_image(&mut src, overlay(), &options);
+        assert!(result.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sort_file_parsing() {
+        let sort =
+            SortFile::parse("# comment\n\n/movie/*.PMF 10 16\nmovie/INTRO.pmf -5\n*.bin 3\n")
+                .unwrap();
+        assert_eq!(sort.rules.len(), 3);
+        assert_eq!(sort.lookup("MOVIE/INTRO.PMF"), (10, 16));
+        assert_eq!(sort.lookup("DATA.BIN"), (3, 1));
+        // Globs don't cross directories.
+        assert_eq!(sort.lookup("DIR/DATA.BIN"), (0, 1));
+        for bad in ["A.BIN", "A.BIN x", "A.BIN 1 3", "A.BIN 1 16 extra", "[ 1"] {
+            assert!(SortFile::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn sorted_placement() {
+        let dir = temp_dir("sort");
+        std::fs::create_dir_all(dir.join("DIR")).unwrap();
+        for (name, size) in [("A.BIN", 3000), ("B.BIN", 100), ("DIR/C.PMF", 5000)] {
+            std::fs::write(dir.join(name), vec![name.as_bytes()[0]; size]).unwrap();
+        }
+        let options = BuildOptions {
+            sort: SortFile::parse("DIR/*.pmf 10 16\nB.BIN 5").unwrap(),
+            ..Default::default()
+        };
+        let mut iso = open_image(build_image(&dir, &options));
+        let sector = |iso: &mut ISO<_>, path| iso.stat(path).unwrap().sector;
+        let (a, b, c) = (
+            sector(&mut iso, "A.BIN"),
+            sector(&mut iso, "B.BIN"),
+            sector(&mut iso, "DIR/C.PMF"),
+        );
+        assert!(c < b && b < a, "{} {} {}", a, b, c);
+        assert_eq!(c % 16, 0);
+        assert_eq!(b, c + 3);
+        assert_eq!(iso.read_file("DIR/C.PMF").unwrap(), vec![b'D'; 5000]);
+        assert_no_errors(&mut iso);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,129 @@
+use std::io::prelude::*;
+
+use serde::Serialize;
+
+use super::dirent::DirEnt;
+use super::iso::ISO;
+
+/// One directory record as it sits on the disc. `path` is `/` for the root
+/// and ends with `/` for directories.
+#[derive(Debug, Clone, Serialize)]
+pub struct Listing {
+    pub path: String,
+    pub lba: u32,
+    pub size: u32,
+    pub sectors: u32,
+    pub flags: u8,
+    /// System use bytes (the XA record) as hex, empty if there are none.
+    pub xa: String,
+    /// RFC 3339, `None` if the recorded date is not valid.
+    pub time: Option<String>,
+}
+
+impl Listing {
+    fn new(path: String, ent: &DirEnt) -> Self {
+        Self {
+            path,
+            lba: ent.sector,
+            size: ent.size,
+            sectors: ent.size.div_ceil(2048),
+            flags: ent.flags,
+            xa: ent
+                .system_use
+                .iter()
+                .map(|x| format!("{:02x}", x))
+                .collect(),
+            time: ent.time.to_chrono().map(|x| x.to_rfc3339()),
+        }
+    }
+}
+
+impl std::fmt::Display for Listing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:>8} {:>6} {:>10} {:02x} {:<28} {:<25} {}",
+            self.lba,
+            self.sectors,
+            self.size,
+            self.flags,
+            if self.xa.is_empty() { "-" } else { &self.xa },
+            self.time.as_deref().unwrap_or("-"),
+            self.path
+        )
+    }
+}
+
+/// Every entry of an image, root first, in the order the directories are
+/// read.
+pub fn list<S: Read + Seek>(iso: &mut ISO<S>) -> std::io::Result<Vec<Listing>> {
+    let root = iso.get_pvd()?.root_ent;
+    // The copy in the PVD has no room for an XA record; its `.` entry does.
+    let root = iso
+        .read_dir_ents(root.sector as u64, root.size)?
+        .into_iter()
+        .next()
+        .unwrap_or(root);
+    let mut listing = vec![Listing::new("/".into(), &root)];
+    for (path, ent) in iso.walk()? {
+        let path = if (ent.flags & 2) != 0 {
+            format!("{}/", path)
+        } else {
+            path
+        };
+        listing.push(Listing::new(path, &ent));
+    }
+    Ok(listing)
+}
+
+/// The entries of the directory at `path`, without its `.` and `..` records.
+pub fn list_dir<S: Read + Seek>(iso: &mut ISO<S>, path: &str) -> std::io::Result<Vec<Listing>> {
+    let dir = path.trim_matches('/');
+    let listing = iso
+        .read_dir(dir)?
+        .iter()
+        .map(|ent| {
+            let path = match dir {
+                "" => ent.name.clone(),
+                _ => format!("{}/{}", dir, ent.name),
+            };
+            let path = if (ent.flags & 2) != 0 {
+                format!("{}/", path)
+            } else {
+                path
+            };
+            Listing::new(path, ent)
+        })
+        .collect();
+    Ok(listing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::iso::tests::{build_image, open_image, temp_dir};
+    use crate::lib::iso::BuildOptions;
+
+    #[test]
+    fn small_image() {
+        let dir = temp_dir("listing");
+        std::fs::create_dir_all(dir.join("DATA")).unwrap();
+        std::fs::write(dir.join("DATA/B.TXT"), b"hello").unwrap();
+        std::fs::write(dir.join("A.BIN"), vec![1; 3000]).unwrap();
+        let mut iso = open_image(build_image(&dir, &BuildOptions::default()));
+        std::fs::remove_dir_all(dir).unwrap();
+
+        let listing = list(&mut iso).unwrap();
+        let paths = listing.iter().map(|x| x.path.as_str()).collect::<Vec<_>>();
+        assert_eq!(paths, ["/", "A.BIN", "DATA/", "DATA/B.TXT"]);
+        let a = &listing[1];
+        assert_eq!((a.size, a.sectors, a.flags), (3000, 2, 0));
+        assert_eq!(a.lba, iso.stat("A.BIN").unwrap().sector);
+        assert_eq!(listing[2].flags & 2, 2);
+
+        let data = list_dir(&mut iso, "/DATA/").unwrap();
+        assert_eq!(data.len(), 1);
+        assert_eq!(data[0].path, "DATA/B.TXT");
+        assert_eq!((data[0].lba, data[0].size), (listing[3].lba, 5));
+    }
+}
@@ -2,6 +2,15 @@
 pub mod iso;
 pub mod dirent;
 pub mod pvd;
+pub mod overlay;
+pub mod block;
+pub mod ciso;
+pub mod checksum;
+pub mod fsck;
+pub mod diff;
+pub mod listing;
+pub mod sort;
+pub mod identify;
 
 #[macro_use]
 pub mod util;
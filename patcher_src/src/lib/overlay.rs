@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+use std::io::{Read, Seek, Write};
+use std::path::PathBuf;
+
+pub trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Contents that are generated while the image is written, so they never
+/// have to be stored anywhere first.
+pub trait Stream {
+    fn size(&self) -> u32;
+    /// Writes exactly `size` bytes. `image` is the source image, for
+    /// anything that is copied out of it.
+    fn write_to(&self, write: &mut dyn Write, image: &mut dyn ReadSeek) -> std::io::Result<()>;
+}
+
+/// Contents for a file that is added to or replaced in the rebuilt image.
+pub enum OverlayData {
+    File(PathBuf),
+    Memory(Vec<u8>),
+    Stream(Box<dyn Stream>),
+}
+
+impl OverlayData {
+    pub fn size(&self) -> std::io::Result<u32> {
+        Ok(match self {
+            OverlayData::File(path) => path.metadata()?.len() as u32,
+            OverlayData::Memory(data) => data.len() as u32,
+            OverlayData::Stream(stream) => stream.size(),
+        })
+    }
+}
+
+/// Changes layered on top of a source image by `ISO::build_from_image`.
+/// Paths are relative to the image root (`PSP_GAME/SYSDIR/EBOOT.BIN`) and
+/// matched case-insensitively. A `None` entry deletes the file.
+#[derive(Default)]
+pub struct Overlay {
+    entries: HashMap<String, (String, Option<OverlayData>)>,
+}
+
+fn normalize(path: &str) -> String {
+    path.trim_matches('/').to_ascii_uppercase()
+}
+
+impl Overlay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds a new file or replaces an existing one.
+    pub fn replace(&mut self, path: &str, data: OverlayData) {
+        let path = path.trim_matches('/');
+        self.entries
+            .insert(normalize(path), (path.to_string(), Some(data)));
+    }
+    pub fn delete(&mut self, path: &str) {
+        let path = path.trim_matches('/');
+        self.entries
+            .insert(normalize(path), (path.to_string(), None));
+    }
+
+    /// Removes and returns the change recorded for `path`, if any.
+    pub(crate) fn take(&mut self, path: &str) -> Option<Option<OverlayData>> {
+        self.entries.remove(&normalize(path)).map(|x| x.1)
+    }
+    /// Whatever is left once the source tree has been walked: files that do
+    /// not exist in the source image yet. Deletions of missing files are dropped.
+    pub(crate) fn into_additions(self) -> Vec<(String, OverlayData)> {
+        let mut additions = self
+            .entries
+            .into_values()
+            .filter_map(|(path, data)| data.map(|x| (path, x)))
+            .collect::<Vec<_>>();
+        additions.sort_by(|a, b| a.0.cmp(&b.0));
+        additions
+    }
+}
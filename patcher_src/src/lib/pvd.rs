@@ -4,7 +4,11 @@ use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
 use chrono::DateTime;
 use chrono::Datelike;
+use chrono::FixedOffset;
 use chrono::Local;
+use chrono::NaiveDate;
+use chrono::Offset;
+use chrono::TimeZone;
 use chrono::Timelike;
 
 use super::dirent::*;
@@ -13,76 +17,150 @@ use std::io::prelude::*;
 use std::io::Read;
 use std::time::SystemTime;
 
-pub struct PVDTime {
-    pub year: u16,
-    pub month: u8,
-    pub day: u8,
-    pub hour: u8,
-    pub minute: u8,
-    pub second: u8,
-    pub centisecond: u8,
-    pub timezone: i8,
+/// A volume descriptor date: 16 ASCII digits and a timezone byte.
+#[derive(Clone)]
+pub enum PVDTime {
+    /// All zeros, or anything that is not a valid date (spaces, NULs),
+    /// kept byte for byte so it is written back unchanged.
+    Unspecified([u8; 17]),
+    Date {
+        year: u16,
+        month: u8,
+        day: u8,
+        hour: u8,
+        minute: u8,
+        second: u8,
+        centisecond: u8,
+        /// Offset from UTC in 15 minute steps.
+        timezone: i8,
+    },
 }
 
 impl std::fmt::Debug for PVDTime {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!(
-            "{}/{}/{} {}:{}:{}.{}{:+}",
-            &self.day,
-            &self.month,
-            &self.year,
-            &self.hour,
-            &self.minute,
-            &self.second,
-            &self.centisecond,
-            &(self.timezone as f32) / 4.0
-        ))
+        match self {
+            PVDTime::Unspecified(_) => f.write_str("unspecified"),
+            PVDTime::Date {
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+                centisecond,
+                timezone,
+            } => f.write_fmt(format_args!(
+                "{}/{}/{} {}:{}:{}.{}{:+}",
+                day,
+                month,
+                year,
+                hour,
+                minute,
+                second,
+                centisecond,
+                (*timezone as f32) / 4.0
+            )),
+        }
     }
 }
 
 impl From<SystemTime> for PVDTime {
     fn from(t: SystemTime) -> Self {
         let time: DateTime<Local> = DateTime::from(t);
+        Self::from_chrono(&time)
+    }
+}
 
-        Self {
+impl PVDTime {
+    pub fn from_chrono<Tz: TimeZone>(time: &DateTime<Tz>) -> Self {
+        PVDTime::Date {
             year: time.year_ce().1 as u16,
             month: time.month() as u8,
             day: time.day() as u8,
             hour: time.hour() as u8,
             minute: time.minute() as u8,
             second: time.second() as u8,
-            centisecond: (time.timestamp_subsec_millis().min(999)/10) as u8,
-            timezone: (time.offset().local_minus_utc() / 60 / 15) as i8,
+            centisecond: (time.timestamp_subsec_millis().min(999) / 10) as u8,
+            timezone: (time.offset().fix().local_minus_utc() / 60 / 15) as i8,
+        }
+    }
+    /// `None` if the date is not specified.
+    pub fn to_chrono(&self) -> Option<DateTime<FixedOffset>> {
+        match *self {
+            PVDTime::Unspecified(_) => None,
+            PVDTime::Date {
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+                centisecond,
+                timezone,
+            } => NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32)?
+                .and_hms_milli_opt(
+                    hour as u32,
+                    minute as u32,
+                    second as u32,
+                    centisecond as u32 * 10,
+                )?
+                .and_local_timezone(FixedOffset::east_opt(timezone as i32 * 15 * 60)?)
+                .single(),
         }
     }
-}
-
-
-impl PVDTime {
     fn write<W: Write + Seek>(&self, write: &mut W) -> std::io::Result<()> {
-        write_string(write, &format!("{:04}", self.year))?;
-        write_string(write, &format!("{:02}", self.month))?;
-        write_string(write, &format!("{:02}", self.day))?;
-        write_string(write, &format!("{:02}", self.hour))?;
-        write_string(write, &format!("{:02}", self.minute))?;
-        write_string(write, &format!("{:02}", self.second))?;
-        write_string(write, &format!("{:02}", self.centisecond))?;
-        write.write_i8(self.timezone)?;
-        Ok(())
+        match self {
+            PVDTime::Unspecified(raw) => write.write_all(raw),
+            PVDTime::Date {
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+                centisecond,
+                timezone,
+            } => {
+                write_string(write, &format!("{:04}", year))?;
+                write_string(write, &format!("{:02}", month))?;
+                write_string(write, &format!("{:02}", day))?;
+                write_string(write, &format!("{:02}", hour))?;
+                write_string(write, &format!("{:02}", minute))?;
+                write_string(write, &format!("{:02}", second))?;
+                write_string(write, &format!("{:02}", centisecond))?;
+                write.write_i8(*timezone)
+            }
+        }
     }
 }
 impl BinaryStruct for PVDTime {
     fn read<R: Read + Seek>(read: &mut R) -> std::io::Result<Box<Self>> {
-        Ok(Box::new(Self {
-            year: read_string_n(read, 4)?.parse().unwrap(),
-            month: read_string_n(read, 2)?.parse().unwrap(),
-            day: read_string_n(read, 2)?.parse().unwrap(),
-            hour: read_string_n(read, 2)?.parse().unwrap(),
-            minute: read_string_n(read, 2)?.parse().unwrap(),
-            second: read_string_n(read, 2)?.parse().unwrap(),
-            centisecond: read_string_n(read, 2)?.parse().unwrap(),
-            timezone: read.read_i8()?,
-        }))
+        let mut raw = [0u8; 17];
+        read.read_exact(&mut raw)?;
+        let digits = &raw[0..16];
+        let number = |start: usize, len: usize| {
+            digits[start..start + len]
+                .iter()
+                .fold(0u16, |acc, x| acc * 10 + (x - b'0') as u16)
+        };
+        if !digits.iter().all(|x| x.is_ascii_digit()) || digits.iter().all(|x| *x == b'0') {
+            return Ok(Box::new(PVDTime::Unspecified(raw)));
+        }
+        let time = PVDTime::Date {
+            year: number(0, 4),
+            month: number(4, 2) as u8,
+            day: number(6, 2) as u8,
+            hour: number(8, 2) as u8,
+            minute: number(10, 2) as u8,
+            second: number(12, 2) as u8,
+            centisecond: number(14, 2) as u8,
+            timezone: raw[16] as i8,
+        };
+        // Out of range fields would not be written back the same way.
+        if time.to_chrono().is_none() {
+            return Ok(Box::new(PVDTime::Unspecified(raw)));
+        }
+        Ok(Box::new(time))
     }
 }
 
@@ -115,6 +193,40 @@ pub struct PVD {
     pub expired: Box<PVDTime>,
     pub effective: Box<PVDTime>,
     pub file_version: u8,
+    /// 512 bytes at 0x373. UMDs keep the disc id and `CD-XA001` here.
+    pub application_use: Vec<u8>,
+}
+
+fn is_d_char(c: char) -> bool {
+    // Spaces and NULs are padding.
+    c.is_ascii_uppercase() || c.is_ascii_digit() || matches!(c, '_' | ' ' | '\0')
+}
+fn is_a_char(c: char) -> bool {
+    is_d_char(c) || "!\"%&'()*+,-./:;<=>?".contains(c)
+}
+
+fn validate(
+    field: &str,
+    value: &str,
+    len: usize,
+    allowed: fn(char) -> bool,
+) -> std::io::Result<()> {
+    if value.len() > len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} \"{}\" is longer than {} characters", field, value, len),
+        ));
+    }
+    if let Some(c) = value.chars().find(|c| !allowed(*c)) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "{} \"{}\" contains {:?}, which ISO 9660 does not allow there",
+                field, value, c
+            ),
+        ));
+    }
+    Ok(())
 }
 
 impl PVD {
@@ -151,6 +263,10 @@ impl PVD {
         self.expired.write(write)?;
         self.effective.write(write)?;
         write.write_u8(self.file_version)?;
+        write.write_u8(0)?;
+        let mut application_use = self.application_use.clone();
+        application_use.resize(512, 0);
+        write.write_all(&application_use)?;
         Ok(())
     }
 }
@@ -187,6 +303,9 @@ impl BinaryStruct for PVD {
         let expired = PVDTime::read(read)?;
         let effective = PVDTime::read(read)?;
         let file_version = read.read_u8()?;
+        read.seek(std::io::SeekFrom::Current(1))?;
+        let mut application_use = vec![0u8; 512];
+        read.read_exact(&mut application_use)?;
 
         Ok(Box::new(Self {
             pvd_type,
@@ -216,6 +335,215 @@ impl BinaryStruct for PVD {
             expired,
             effective,
             file_version,
+            application_use,
         }))
     }
 }
+
+/// Everything in front of the path tables that a rebuild carries over from
+/// the source image.
+pub struct VolumeHeader {
+    /// Sectors 0-15.
+    pub system_area: Vec<u8>,
+    pub pvd: Box<PVD>,
+    /// Raw sectors of the rest of the descriptor set, ending with the terminator.
+    pub descriptors: Vec<Vec<u8>>,
+}
+
+/// Volume fields to override when building, e.g. from `dist/volume.txt`.
+/// Unset fields are copied from the source.
+#[derive(Debug, Clone, Default)]
+pub struct VolumeMetadata {
+    pub system_id: Option<String>,
+    pub volume_id: Option<String>,
+    pub set_id: Option<String>,
+    pub pub_id: Option<String>,
+    pub prep_id: Option<String>,
+    pub app_id: Option<String>,
+    pub created: Option<PVDTime>,
+    pub modified: Option<PVDTime>,
+    pub expired: Option<PVDTime>,
+    pub effective: Option<PVDTime>,
+}
+
+/// `YYYYMMDDhhmmss` or `YYYYMMDDhhmmsscc`, in UTC.
+fn parse_pvd_time(value: &str) -> std::io::Result<PVDTime> {
+    let digits = match value.len() {
+        14 => format!("{}00", value),
+        _ => value.to_string(),
+    };
+    if digits.len() != 16 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("\"{}\" is not a YYYYMMDDhhmmss time", value),
+        ));
+    }
+    let mut buff = digits.into_bytes();
+    buff.push(0);
+    match *PVDTime::read(&mut std::io::Cursor::new(buff))? {
+        PVDTime::Unspecified(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("\"{}\" is not a valid date", value),
+        )),
+        time => Ok(time),
+    }
+}
+
+impl VolumeMetadata {
+    /// Reads `key = value` lines; `#` starts a comment. Keys are the field names.
+    pub fn parse(text: &str) -> std::io::Result<Self> {
+        let mut metadata = Self::default();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Expected key = value: {}", line),
+                )
+            })?;
+            let value = value.trim().to_string();
+            match key.trim() {
+                "system_id" => metadata.system_id = Some(value),
+                "volume_id" => metadata.volume_id = Some(value),
+                "set_id" => metadata.set_id = Some(value),
+                "pub_id" => metadata.pub_id = Some(value),
+                "prep_id" => metadata.prep_id = Some(value),
+                "app_id" => metadata.app_id = Some(value),
+                "created" => metadata.created = Some(parse_pvd_time(&value)?),
+                "modified" => metadata.modified = Some(parse_pvd_time(&value)?),
+                "expired" => metadata.expired = Some(parse_pvd_time(&value)?),
+                "effective" => metadata.effective = Some(parse_pvd_time(&value)?),
+                key => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Unknown volume field {}", key),
+                    ))
+                }
+            }
+        }
+        metadata.validate()?;
+        Ok(metadata)
+    }
+    /// Checks the overridden identifiers against their ISO 9660 character
+    /// sets (a-characters or d-characters) and lengths. Fields copied from the
+    /// source are written as they are.
+    fn validate(&self) -> std::io::Result<()> {
+        let strings = [
+            ("System identifier", &self.system_id, 32, is_a_char as fn(char) -> bool),
+            ("Volume identifier", &self.volume_id, 32, is_d_char),
+            ("Volume set identifier", &self.set_id, 128, is_d_char),
+            ("Publisher identifier", &self.pub_id, 128, is_a_char),
+            ("Data preparer identifier", &self.prep_id, 128, is_a_char),
+            ("Application identifier", &self.app_id, 128, is_a_char),
+        ];
+        for (field, value, len, allowed) in strings {
+            if let Some(value) = value {
+                validate(field, value, len, allowed)?;
+            }
+        }
+        Ok(())
+    }
+    pub fn load(path: &std::path::Path) -> std::io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+    /// Whether no field is overridden, i.e. the volume descriptor is copied
+    /// as is.
+    pub fn is_empty(&self) -> bool {
+        let strings = [
+            &self.system_id,
+            &self.volume_id,
+            &self.set_id,
+            &self.pub_id,
+            &self.prep_id,
+            &self.app_id,
+        ];
+        let times = [&self.created, &self.modified, &self.expired, &self.effective];
+        strings.iter().all(|x| x.is_none()) && times.iter().all(|x| x.is_none())
+    }
+    pub fn apply(&self, pvd: &mut PVD) {
+        let strings = [
+            (&self.system_id, &mut pvd.system_id),
+            (&self.volume_id, &mut pvd.volume_id),
+            (&self.set_id, &mut pvd.set_id),
+            (&self.pub_id, &mut pvd.pub_id),
+            (&self.prep_id, &mut pvd.prep_id),
+            (&self.app_id, &mut pvd.app_id),
+        ];
+        for (value, field) in strings {
+            if let Some(value) = value {
+                *field = value.clone();
+            }
+        }
+        let times = [
+            (&self.created, &mut pvd.created),
+            (&self.modified, &mut pvd.modified),
+            (&self.expired, &mut pvd.expired),
+            (&self.effective, &mut pvd.effective),
+        ];
+        for (value, field) in times {
+            if let Some(value) = value {
+                **field = value.clone();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::iso::tests::volume_header;
+
+    #[test]
+    fn only_overridden_fields_are_validated() {
+        assert!(VolumeMetadata::parse("volume_id = umd data").is_err());
+        assert!(VolumeMetadata::parse(&format!("app_id = {}", "A".repeat(129))).is_err());
+        let metadata = VolumeMetadata::parse("app_id = P2EP ENGLISH PATCH").unwrap();
+        // Source dumps don't always stick to the character sets.
+        let mut pvd = volume_header().pvd;
+        pvd.pub_id = "publisher, inc.".into();
+        metadata.apply(&mut pvd);
+        let mut out = std::io::Cursor::new(Vec::new());
+        pvd.write(&mut out).unwrap();
+        assert_eq!(pvd.app_id, "P2EP ENGLISH PATCH");
+    }
+
+    fn round_trip(raw: &[u8; 17]) -> PVDTime {
+        let time = *PVDTime::read(&mut std::io::Cursor::new(raw)).unwrap();
+        let mut out = std::io::Cursor::new(Vec::new());
+        time.write(&mut out).unwrap();
+        assert_eq!(out.into_inner(), raw, "{:?}", time);
+        time
+    }
+
+    #[test]
+    fn unspecified_times_are_kept() {
+        let mut zeros = [b'0'; 17];
+        zeros[16] = 0;
+        let mut spaces = [b' '; 17];
+        spaces[16] = 0;
+        let mut bad_month = *b"20121314112100000";
+        bad_month[16] = 36;
+        for raw in [zeros, spaces, [0; 17], bad_month] {
+            assert!(matches!(round_trip(&raw), PVDTime::Unspecified(_)));
+        }
+    }
+
+    #[test]
+    fn dates_round_trip() {
+        let mut raw = *b"20120314112100420";
+        raw[16] = 36;
+        let time = round_trip(&raw);
+        let expected = FixedOffset::east_opt(9 * 3600)
+            .unwrap()
+            .with_ymd_and_hms(2012, 3, 14, 11, 21, 0)
+            .unwrap()
+            + chrono::Duration::milliseconds(420);
+        assert_eq!(time.to_chrono(), Some(expected));
+        raw[16] = (-20i8) as u8;
+        let time = round_trip(&raw);
+        assert_eq!(time.to_chrono().unwrap().offset().local_minus_utc(), -5 * 3600);
+    }
+}
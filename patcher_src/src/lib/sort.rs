@@ -0,0 +1,81 @@
+use glob::{MatchOptions, Pattern};
+
+/// One line of a sort file.
+#[derive(Debug, Clone)]
+pub struct SortRule {
+    pub pattern: Pattern,
+    pub weight: i32,
+    /// Sectors to align the start of the file to; 1 for none.
+    pub align: u32,
+}
+
+/// Placement of files when building, in the spirit of `mkisofs -sort`:
+///
+/// ```text
+/// <path> <weight> [<alignment in sectors>]
+/// ```
+///
+/// Files with a higher weight are placed first; unlisted files weigh 0 and
+/// keep their tree order. `path` is a glob from the image root
+/// (`PSP_GAME/USRDIR/movie/*.pmf`), matched case-insensitively, and the
+/// first matching line wins. Alignment must be a power of two.
+#[derive(Debug, Clone, Default)]
+pub struct SortFile {
+    pub rules: Vec<SortRule>,
+}
+
+impl SortFile {
+    pub fn parse(text: &str) -> std::io::Result<Self> {
+        let mut rules = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |message: &str| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Sort file line {}: {}", i + 1, message),
+                )
+            };
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            if fields.len() < 2 || fields.len() > 3 {
+                return Err(invalid("expected <path> <weight> [<alignment>]"));
+            }
+            let pattern =
+                Pattern::new(fields[0].trim_matches('/')).map_err(|e| invalid(&e.to_string()))?;
+            let weight = fields[1]
+                .parse()
+                .map_err(|_| invalid("weight is not a number"))?;
+            let align = match fields.get(2) {
+                Some(x) => x
+                    .parse::<u32>()
+                    .ok()
+                    .filter(|x| x.is_power_of_two())
+                    .ok_or_else(|| invalid("alignment is not a power of two"))?,
+                None => 1,
+            };
+            rules.push(SortRule {
+                pattern,
+                weight,
+                align,
+            });
+        }
+        Ok(Self { rules })
+    }
+    pub fn load(path: &std::path::Path) -> std::io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+    /// Weight and alignment for the file at `path`.
+    pub fn lookup(&self, path: &str) -> (i32, u32) {
+        let options = MatchOptions {
+            case_sensitive: false,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        self.rules
+            .iter()
+            .find(|x| x.pattern.matches_with(path, options))
+            .map_or((0, 1), |x| (x.weight, x.align))
+    }
+}
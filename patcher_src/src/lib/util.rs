@@ -15,13 +15,23 @@ pub trait BinaryStruct {
 pub fn read_u16bi<R: Read + Seek>(read: &mut R) -> std::io::Result<u16> {
     let le = read.read_u16::<LittleEndian>()?;
     let be = read.read_u16::<BigEndian>()?;
-    assert_eq!(le, be);
+    if le != be {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Both-endian field disagrees: {:#x} (LE) vs {:#x} (BE)", le, be),
+        ));
+    }
     Ok(le)
 }
 pub fn read_u32bi<R: Read + Seek>(read: &mut R) -> std::io::Result<u32> {
     let le = read.read_u32::<LittleEndian>()?;
     let be = read.read_u32::<BigEndian>()?;
-    assert_eq!(le, be);
+    if le != be {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Both-endian field disagrees: {:#x} (LE) vs {:#x} (BE)", le, be),
+        ));
+    }
     Ok(le)
 }
 
@@ -88,7 +98,12 @@ pub fn read_string_trim<R: Read + Seek>(r: &mut R, n: usize) -> std::io::Result<
         .to_string())
 }
 pub fn write_string_pad<W: Write + Seek>(w: &mut W, str: &str, n: usize) -> std::io::Result<()> {
-
+    if str.len() > n {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("\"{}\" is longer than {} bytes", str, n),
+        ));
+    }
     w.write_all(str.as_bytes())?;
     for _ in 0..n-str.len() {
         w.write_u8(0x20)?; //space
@@ -1,14 +1,32 @@
 mod lib;
 use byteorder::{LittleEndian, ReadBytesExt};
-use lib::{cpk::CPK, iso::ISO, util::BinaryStruct};
+use glob::Pattern;
+use lib::{
+    cpk::{CPKImage, CPK},
+    iso::ISO,
+    util::BinaryStruct,
+};
 use std::{
     fs::{File, OpenOptions},
-    io::{Seek, SeekFrom, Write},
+    io::Cursor,
     path::{Path, PathBuf},
     prelude::*,
 };
 
-use crate::lib::{event::EventArch, iso::ISODirent};
+use crate::lib::{
+    checksum::{self, Checksums},
+    ciso::{CSOFormat, CSOOptions},
+    diff,
+    dirent::DirEntTime,
+    event::EventArch,
+    fsck::{self, Severity},
+    identify::{self, KnownDumps},
+    iso::{BuildOptions, ExtractOptions, ImageFormat, LayoutMode},
+    listing,
+    overlay::{Overlay, OverlayData},
+    pvd::VolumeMetadata,
+    sort::SortFile,
+};
 
 extern "C" {
     pub fn pspDecryptPRX(
@@ -18,54 +36,56 @@ extern "C" {
     ) -> cty::c_int;
 }
 
-#[allow(dead_code)]
-fn remove_extraneous() -> std::io::Result<()> {
-    std::fs::remove_file("iso/PSP_GAME/INSDIR/I020.DAT")?;
-    std::fs::remove_file("iso/PSP_GAME/SYSDIR/UPDATE/DATA.BIN")?;
-    std::fs::remove_file("iso/PSP_GAME/SYSDIR/UPDATE/EBOOT.BIN")?;
-    std::fs::remove_file("iso/PSP_GAME/SYSDIR/BOOT.BIN")?;
-    std::fs::remove_file("iso/PSP_GAME/SYSDIR/UPDATE/PARAM.SFO")
+const EBOOT_PATH: &str = "PSP_GAME/SYSDIR/EBOOT.BIN";
+const PARAM_PATH: &str = "PSP_GAME/PARAM.SFO";
+const CPK_PATH: &str = "PSP_GAME/USRDIR/pack/P2PT_ALL.cpk";
+const SUPPORTED: [&str; 2] = ["NPJH50581", "ULJM06081"];
+/// The CPK member holding the event scripts.
+const EVENT_ID: u32 = 6000;
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
 }
-#[allow(dead_code)]
-fn copy_eng() -> std::io::Result<()> {
-    std::fs::copy("dist/ENG.BIN", "iso/PSP_GAME/USRDIR/ENG.BIN")?;
-    Ok(())
+/// The `dist` directory next to the executable, so that the patch files and
+/// configuration are found wherever the patcher is run from.
+fn dist_dir() -> std::io::Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    let dir = exe
+        .parent()
+        .ok_or_else(|| invalid(format!("{} has no parent directory", exe.display())))?;
+    Ok(dir.join("dist"))
 }
-#[allow(dead_code)]
-fn extract_iso(path: &Path) -> std::io::Result<()> {
-    println!("Extracting iso");
-    let file = File::open(path)?;
-    let mut iso = ISO::new(file);
-    iso.extract(Path::new("./iso/"))
+
+fn remove_extraneous(overlay: &mut Overlay) {
+    overlay.delete("PSP_GAME/INSDIR/I020.DAT");
+    overlay.delete("PSP_GAME/SYSDIR/UPDATE/DATA.BIN");
+    overlay.delete("PSP_GAME/SYSDIR/UPDATE/EBOOT.BIN");
+    overlay.delete("PSP_GAME/SYSDIR/BOOT.BIN");
+    overlay.delete("PSP_GAME/SYSDIR/UPDATE/PARAM.SFO");
+}
+fn copy_eng(overlay: &mut Overlay) {
+    overlay.replace(
+        "PSP_GAME/USRDIR/ENG.BIN",
+        OverlayData::File(PathBuf::from("dist/ENG.BIN")),
+    );
 }
 
-#[allow(dead_code)]
-fn apply_xdelta_patch(src: &Path, patch: &Path) -> std::io::Result<()> {
+fn apply_xdelta_patch(data: &[u8], patch: &Path) -> std::io::Result<Vec<u8>> {
     println!("Applying patch {}", patch.to_str().unwrap());
     let patch_data = std::fs::read(patch)?;
-    let data = std::fs::read(src)?;
-    let res = xdelta3::decode(&patch_data, &data);
+    let res = xdelta3::decode(&patch_data, data);
     match res {
-        Some(patched) => std::fs::write(src, &patched),
+        Some(patched) => Ok(patched),
         None => panic!("Failed to apply patch {}", patch.to_str().unwrap()),
     }
 }
-#[allow(dead_code)]
-fn apply_misc_patches() -> std::io::Result<()> {
-    let patches = vec![
-        ("iso/PSP_GAME/SYSDIR/", "EBOOT.BIN"),
-        ("iso/PSP_GAME/", "PARAM.SFO"),
-    ];
-    patches.into_iter().try_for_each(|x| {
-        let mut path: PathBuf = x.0.into();
-        path.push(x.1);
-        let mut patch_path = PathBuf::from("dist/");
-        patch_path.push(&format!("{}.patch", x.1));
-        apply_xdelta_patch(&path, &patch_path)
-    })
-}
-fn decrypt_eboot() -> std::io::Result<()> {
-    let eboot = std::fs::read("iso/PSP_GAME/SYSDIR/EBOOT.BIN")?;
+fn apply_misc_patches(iso: &mut ISO, eboot: &mut Vec<u8>) -> std::io::Result<Vec<u8>> {
+    *eboot = apply_xdelta_patch(eboot, Path::new("dist/EBOOT.BIN.patch"))?;
+    let param = iso.read_file(PARAM_PATH)?;
+    apply_xdelta_patch(&param, Path::new("dist/PARAM.SFO.patch"))
+}
+fn decrypt_eboot(iso: &mut ISO) -> std::io::Result<Vec<u8>> {
+    let eboot = iso.read_file(EBOOT_PATH)?;
     let elf_size = (&eboot[0x28..0x30]).read_u32::<LittleEndian>()?;
     let psp_size = (&eboot[0x2c..0x30]).read_u32::<LittleEndian>()?;
     let size = elf_size.max(psp_size);
@@ -74,49 +94,81 @@ fn decrypt_eboot() -> std::io::Result<()> {
     if res < 0 {
         panic!("Unable to decrypt eboot.");
     }
-    std::fs::write("iso/PSP_GAME/SYSDIR/EBOOT.BIN", &output[0..res as usize])
+    output.truncate(res as usize);
+    Ok(output)
 }
 
-#[allow(dead_code)]
-fn extract_cpk() -> std::io::Result<()> {
-    std::fs::create_dir_all("cpk")?;
-    let mut file = File::open("iso/PSP_GAME/USRDIR/pack/P2PT_ALL.cpk")?;
+/// Patches the members of the game's CPK that have a
+/// `dist/cpk_dist/<name>.patch`, and the event archive along with `eboot`.
+/// New members are added from `dist/cpk_add/<id>.bin`, and the IDs listed in
+/// `dist/cpk_remove.txt` are dropped. The rebuilt archive is streamed into
+/// the new image, copying unchanged members straight from the source.
+fn build_cpk(iso: &mut ISO, eboot: &mut [u8]) -> std::io::Result<CPKImage> {
+    let base = iso.stat(CPK_PATH)?.sector as u64 * 2048;
+    let mut file = iso.open(CPK_PATH)?;
     let mut cpk = CPK::read(&mut file)?;
-    cpk.map_files(&mut file, |x, y| {
-        println!("Extract cpk file [{}]{}", x.id, x.name);
+    let mut source = Vec::new();
+    for x in cpk.files()? {
         let patch_path = PathBuf::from(format!("dist/cpk_dist/{}.patch", &x.name));
-        let out_data = if patch_path.exists() {
-            let patch_data = std::fs::read(&patch_path)?;
-            let res = xdelta3::decode(&patch_data, &y);
-            match res {
-                Some(patched) => patched,
-                None => panic!("Failed to apply patch {}", patch_path.to_str().unwrap()),
-            }
+        if !patch_path.exists() && x.id != EVENT_ID {
+            continue;
+        }
+        println!("Patch cpk file [{}]{}", x.id, x.name);
+        cpk.read_file_into(&mut file, &x, &mut source)?;
+        let mut data = if patch_path.exists() {
+            apply_xdelta_patch(&source, &patch_path)?
         } else {
-            y
+            std::mem::take(&mut source)
         };
-        std::fs::write(format!("cpk/{}.bin", x.id), out_data)
-    })
-}
-
-#[allow(dead_code)]
-fn build_cpk() -> std::io::Result<()> {
-    // let mut file = File::open("P2PT_ALL.cpk")?;
-    let mut file = File::open("iso/PSP_GAME/USRDIR/pack/P2PT_ALL.cpk")?;
-    let mut cpk = CPK::read(&mut file)?;
-    let mut out = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .read(true)
-        .truncate(true)
-        .open("iso/PSP_GAME/USRDIR/pack/P2PT_ALL.cpk")?;
-
-    cpk.write_cpk(PathBuf::from("cpk/"), &mut out)
+        if x.id == EVENT_ID {
+            data = patch_event(data, eboot)?;
+        }
+        cpk.replace_file(x.id, data)?;
+    }
+    let add = Path::new("dist/cpk_add");
+    if add.is_dir() {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(add)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            let id = name
+                .strip_suffix(".bin")
+                .and_then(|x| x.parse::<u32>().ok())
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("dist/cpk_add/{}: expected a <id>.bin file", name),
+                    )
+                })?;
+            entries.push((id, name));
+        }
+        entries.sort();
+        for (id, name) in entries {
+            println!("Add cpk file {}", name);
+            cpk.add_file(id, &name, std::fs::read(add.join(&name))?)?;
+        }
+    }
+    let remove = Path::new("dist/cpk_remove.txt");
+    if remove.exists() {
+        for line in std::fs::read_to_string(remove)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let id = line.parse().map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("dist/cpk_remove.txt: {} is not a member ID", line),
+                )
+            })?;
+            cpk.remove_file(id)?;
+        }
+    }
+    cpk.layout(&mut file, base)
 }
 
-#[allow(dead_code)]
-fn patch_event() -> std::io::Result<()> {
-    let data = std::fs::read("cpk/6000.bin")?;
+/// Applies `dist/event_dist/<name>.patch` to the scripts in the event
+/// archive, and points `eboot` at the new script table.
+fn patch_event(data: Vec<u8>, eboot: &mut [u8]) -> std::io::Result<Vec<u8>> {
     let mut event: EventArch = EventArch::try_from(data)?;
 
     event.map_scripts(|name, event| {
@@ -136,74 +188,366 @@ fn patch_event() -> std::io::Result<()> {
         })
     })?;
 
-    let mut output = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .read(true)
-        .truncate(true)
-        .open("cpk/6000.bin")?;
+    let mut output = Cursor::new(Vec::new());
     let toc = event.write(&mut output)?;
 
-    let mut eboot = OpenOptions::new()
-        .read(true)
-        .write(true)
-        .truncate(false)
-        .open("iso/PSP_GAME/SYSDIR/EBOOT.BIN")?;
-    eboot.seek(SeekFrom::Start(0x8c570c4 + 0xc0 - 0x8804000))?;
-    eboot.write_all(&toc)?;
-    // eboot.write_all_at(&toc, 0x8c570c4 + 0xc0 - 0x8804000)?;
-
-    Ok(())
-
-    // dbg!(&event);
-    // let file = File::open("cpk/6000.bin")?;
+    let off = 0x8c570c4 + 0xc0 - 0x8804000;
+    eboot[off..off + toc.len()].copy_from_slice(&toc);
 
-    // Ok(())
+    Ok(output.into_inner())
 }
 
-#[allow(dead_code)]
-fn build_iso(path: &Path) -> std::io::Result<()> {
+fn build_iso(
+    iso: &mut ISO,
+    overlay: Overlay,
+    options: &BuildOptions,
+) -> std::io::Result<Checksums> {
     println!("Building iso... This may take a minute");
-    let original = File::open(path)?;
-    let mut out = OpenOptions::new()
+    let name = match &options.format {
+        ImageFormat::Raw => "P2EP_EN.iso",
+        ImageFormat::Compressed(cso) if cso.format == CSOFormat::ZSO => "P2EP_EN.zso",
+        ImageFormat::Compressed(_) => "P2EP_EN.cso",
+    };
+    let out = OpenOptions::new()
         .create(true)
         .write(true)
         .read(true)
         .truncate(true)
-        .open("P2EP_EN.iso")?;
-
-    let mut old_iso = ISO::new(original);
-    let mut iso = ISO::new(out);
-    let pvd = old_iso.get_pvd()?;
-    iso.build_from_dir(pvd, PathBuf::from("iso/"))?;
+        .open(name)?;
 
+    let mut new_iso = ISO::new(out);
+    new_iso.build_from_image(iso, overlay, options)
+}
+/// Output options following the iso path: `--cso`/`--zso` to write a
+/// compressed image, with `--block-size <bytes>` and `--level <0-9>`, and
+/// `--preserve-layout` to keep unchanged files at their original sectors.
+/// New and patched files are stamped with `SOURCE_DATE_EPOCH` when it is set,
+/// and volume fields are overridden by `dist/volume.txt` if it exists. Files
+/// are placed by `--sort <file>` (relative to `cwd`), or `dist/sort.txt`.
+/// Both `dist` files are looked up next to the executable.
+fn parse_build_options(args: &[String], cwd: &Path) -> std::io::Result<BuildOptions> {
+    let with_path = |path: &Path, e: std::io::Error| {
+        std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+    };
+    let mut options = BuildOptions::default();
+    let mut cso = CSOOptions::default();
+    let mut compressed = false;
+    let mut sort = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = |what: &str| {
+            args.next()
+                .ok_or_else(|| invalid(format!("{} needs {}", arg, what)))
+        };
+        match arg.as_str() {
+            "--cso" => {
+                compressed = true;
+                cso.format = CSOFormat::CSO;
+            }
+            "--zso" => {
+                compressed = true;
+                cso.format = CSOFormat::ZSO;
+            }
+            "--preserve-layout" => options.layout = LayoutMode::Preserve,
+            "--sort" => sort = Some(cwd.join(value("a file")?)),
+            "--block-size" => {
+                let what = "a multiple of 2048 bytes";
+                let x = value(what)?;
+                cso.block_size = x
+                    .parse()
+                    .ok()
+                    .filter(|x: &u32| *x != 0 && x.is_multiple_of(2048))
+                    .ok_or_else(|| invalid(format!("--block-size needs {}, not {}", what, x)))?;
+            }
+            "--level" => {
+                let what = "a number from 0 to 9";
+                let x = value(what)?;
+                cso.level = x
+                    .parse()
+                    .ok()
+                    .filter(|x| *x <= 9)
+                    .ok_or_else(|| invalid(format!("--level needs {}, not {}", what, x)))?;
+            }
+            _ => return Err(invalid(format!("Unknown option {}", arg))),
+        }
+    }
+    if compressed {
+        options.format = ImageFormat::Compressed(cso);
+    }
+    let dist = dist_dir()?;
+    let volume = dist.join("volume.txt");
+    if volume.exists() {
+        options.metadata = VolumeMetadata::load(&volume).map_err(|e| with_path(&volume, e))?;
+    }
+    let sort = sort.or_else(|| Some(dist.join("sort.txt")).filter(|x| x.exists()));
+    if let Some(sort) = sort {
+        options.sort = SortFile::load(&sort).map_err(|e| with_path(&sort, e))?;
+    }
+    if let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+        let secs = epoch.parse().map_err(|_| {
+            invalid(format!(
+                "SOURCE_DATE_EPOCH needs a number of seconds, not {}",
+                epoch
+            ))
+        })?;
+        options.timestamp = DirEntTime::from_epoch(secs)
+            .map_err(|e| invalid(format!("SOURCE_DATE_EPOCH: {}", e)))?;
+    }
+    Ok(options)
+}
+/// Makes sure the source is a clean dump of a supported version before
+/// anything is written, using the hashes in `dist/known_dumps.txt`.
+fn identify_source(iso: &mut ISO) -> std::io::Result<String> {
+    println!("Checking source image...");
+    let known = KnownDumps::load(Path::new("dist/known_dumps.txt"))?;
+    let identity =
+        identify::identify(iso, PARAM_PATH, &[EBOOT_PATH, PARAM_PATH, CPK_PATH], &known)?;
+    if let Err(e) = known.check(&identity, &SUPPORTED) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    if identity.hashes.is_empty() {
+        println!(
+            "Warning: dist/known_dumps.txt lists no hashes for {}, so the dump could not be checked.",
+            identity.variant
+        );
+    }
+    println!("Source is {}.", identity.variant);
+    Ok(identity.variant)
+}
+/// Compares the output against the checksums in `dist/expected_output.txt`
+/// for this source and layout. CSO/ZSO output is checked by the checksums
+/// of the image inside it. The expected checksums are for the default date,
+/// volume fields and file order, so the comparison is skipped when any of
+/// them is changed.
+fn verify_output(
+    variant: &str,
+    options: &BuildOptions,
+    checksums: &Checksums,
+) -> std::io::Result<()> {
+    let layout = match options.layout {
+        LayoutMode::Packed => "packed",
+        LayoutMode::Preserve => "preserve",
+    };
+    println!("Output: {}", checksums);
+    let custom = [
+        (
+            "SOURCE_DATE_EPOCH",
+            std::env::var_os("SOURCE_DATE_EPOCH").is_some(),
+        ),
+        ("the sort file", !options.sort.rules.is_empty()),
+        ("dist/volume.txt", !options.metadata.is_empty()),
+    ]
+    .iter()
+    .filter(|x| x.1)
+    .map(|x| x.0)
+    .collect::<Vec<_>>();
+    if !custom.is_empty() {
+        println!(
+            "Not comparing with the expected checksum, the output is customized by {}.",
+            custom.join(", ")
+        );
+        return Ok(());
+    }
+    match checksum::load_expected(Path::new("dist/expected_output.txt"), variant, layout)? {
+        Some(expected) if &expected == checksums => println!("Checksum PASS"),
+        Some(expected) => {
+            println!("Checksum FAIL, expected {}", expected);
+            println!("The patched image is not the expected one. Please check your source dump and patch files.");
+        }
+        None => println!(
+            "No expected checksum for {} with the {} layout.",
+            variant, layout
+        ),
+    }
     Ok(())
-    // cpk.write_cpk(PathBuf::from("cpk/"), &mut out)
 }
-fn cleanup() -> std::io::Result<()> {
-    std::fs::remove_dir_all("iso")?;
-    std::fs::remove_dir_all("cpk")
+/// `patcher fsck <iso>`: lists structural problems in an image and exits
+/// with status 1 if any of them are errors.
+fn fsck(path: &str) -> std::io::Result<()> {
+    let mut iso = ISO::from_file(File::open(path)?)?;
+    let findings = fsck::check(&mut iso)?;
+    for finding in findings.iter() {
+        println!("{}", finding);
+    }
+    if findings.iter().any(|x| x.severity == Severity::Error) {
+        std::process::exit(1);
+    }
+    println!("No errors found.");
+    Ok(())
+}
+/// `patcher diff <old iso> <new iso>`: lists the files and volume fields
+/// that differ between two images.
+fn diff(old: &str, new: &str) -> std::io::Result<()> {
+    let mut old = ISO::from_file(File::open(old)?)?;
+    let mut new = ISO::from_file(File::open(new)?)?;
+    let diffs = diff::diff(&mut old, &mut new)?;
+    for difference in diffs.iter() {
+        println!("{}", difference);
+    }
+    if diffs.is_empty() {
+        println!("Images are identical.");
+    }
+    Ok(())
+}
+/// `patcher verify-cpk <iso or cpk>`: checks the table and member CRCs of a
+/// CPK archive, or of the game's archive inside an image.
+fn verify_cpk(path: &str) -> std::io::Result<()> {
+    let mismatches = if path.to_ascii_lowercase().ends_with(".cpk") {
+        let mut file = File::open(path)?;
+        CPK::read(&mut file)?.verify(&mut file)?
+    } else {
+        let mut iso = ISO::from_file(File::open(path)?)?;
+        let mut file = iso.open(CPK_PATH)?;
+        CPK::read(&mut file)?.verify(&mut file)?
+    };
+    for mismatch in mismatches.iter() {
+        println!("{}", mismatch);
+    }
+    if !mismatches.is_empty() {
+        std::process::exit(1);
+    }
+    println!("All CRCs match.");
+    Ok(())
+}
+/// `patcher ls <iso> [<dir>] [--json]`: prints every directory record, or
+/// those in `dir`, with their LBA, size, sector count, flags, XA bytes and
+/// time.
+fn ls(path: &str, dir: Option<&String>, json: bool) -> std::io::Result<()> {
+    let mut iso = ISO::from_file(File::open(path)?)?;
+    let listing = match dir {
+        Some(dir) => listing::list_dir(&mut iso, dir)?,
+        None => listing::list(&mut iso)?,
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&listing)?);
+        return Ok(());
+    }
+    println!(
+        "{:>8} {:>6} {:>10} {:2} {:<28} {:<25} PATH",
+        "LBA", "SECTS", "SIZE", "FL", "XA", "TIME"
+    );
+    for entry in listing.iter() {
+        println!("{}", entry);
+    }
+    Ok(())
+}
+/// `patcher extract <iso> <dir> [--include <glob>]... [--exclude <glob>]...
+/// [--preserve-times]`: extracts some or all of an image.
+fn extract(args: &[String]) -> std::io::Result<()> {
+    let usage = || invalid("Usage: patcher extract <iso> <dir> [options]".into());
+    let mut iso = ISO::from_file(File::open(args.first().ok_or_else(usage)?)?)?;
+    let dir = PathBuf::from(args.get(1).ok_or_else(usage)?);
+    let mut options = ExtractOptions::default();
+    let mut args = args[2..].iter();
+    while let Some(arg) = args.next() {
+        let mut pattern = || {
+            let x = args
+                .next()
+                .ok_or_else(|| invalid(format!("{} needs a glob pattern", arg)))?;
+            Pattern::new(x)
+                .map_err(|e| invalid(format!("{}: invalid glob pattern {}: {}", arg, x, e)))
+        };
+        match arg.as_str() {
+            "--include" => options.include.push(pattern()?),
+            "--exclude" => options.exclude.push(pattern()?),
+            "--preserve-times" => options.preserve_times = true,
+            _ => return Err(invalid(format!("Unknown option {}", arg))),
+        }
+    }
+    iso.extract_with(&dir, &options)
+}
+/// `patcher build <source iso> <dir> <output> [options]`: builds an image
+/// from the files in `dir`, keeping the system area and volume descriptors of
+/// the source image. Takes the same output options as patching.
+fn build(args: &[String]) -> std::io::Result<()> {
+    let usage = || invalid("Usage: patcher build <source iso> <dir> <output> [options]".into());
+    let mut src = ISO::from_file(File::open(args.first().ok_or_else(usage)?)?)?;
+    let dir = PathBuf::from(args.get(1).ok_or_else(usage)?);
+    let out = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .read(true)
+        .truncate(true)
+        .open(args.get(2).ok_or_else(usage)?)?;
+    let options = parse_build_options(&args[3..], &std::env::current_dir()?)?;
+    let volume = src.get_volume_header()?;
+    let checksums = ISO::new(out).build_from_dir(volume, dir, &options)?;
+    println!("Output: {}", checksums);
+    Ok(())
+}
+/// `patcher replace <iso> <path> <file> [--relocate]`: overwrites one file
+/// of a raw image in place; see `ISO::replace_file`.
+fn replace(args: &[String]) -> std::io::Result<()> {
+    let usage = || invalid("Usage: patcher replace <iso> <path> <file> [--relocate]".into());
+    let fp = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(args.first().ok_or_else(usage)?)?;
+    let path = args.get(1).ok_or_else(usage)?;
+    let data = std::fs::read(args.get(2).ok_or_else(usage)?)?;
+    let mut relocate = false;
+    for arg in args[3..].iter() {
+        match arg.as_str() {
+            "--relocate" => relocate = true,
+            _ => return Err(invalid(format!("Unknown option {}", arg))),
+        }
+    }
+    ISO::from_file(fp)?.replace_file(path, &data, relocate)?;
+    println!("Replaced {}.", path);
+    Ok(())
 }
 fn main() -> std::io::Result<()> {
+    let args = std::env::args().collect::<Vec<_>>();
+    match args.get(1).map(|x| x.as_str()) {
+        Some("extract") => return extract(&args[2..]),
+        Some("build") => return build(&args[2..]),
+        Some("replace") => return replace(&args[2..]),
+        Some("fsck") => return fsck(args.get(2).expect("Usage: patcher fsck <iso>")),
+        Some("verify-cpk") => {
+            return verify_cpk(args.get(2).expect("Usage: patcher verify-cpk <iso or cpk>"))
+        }
+        Some("ls") => {
+            return ls(
+                args.get(2)
+                    .expect("Usage: patcher ls <iso> [<dir>] [--json]"),
+                args.iter().skip(3).find(|x| *x != "--json"),
+                args.iter().any(|x| x == "--json"),
+            )
+        }
+        Some("diff") => {
+            return diff(
+                args.get(2)
+                    .expect("Usage: patcher diff <old iso> <new iso>"),
+                args.get(3)
+                    .expect("Usage: patcher diff <old iso> <new iso>"),
+            )
+        }
+        _ => (),
+    }
     let mut iso_path = PathBuf::from(
-        std::env::args()
-            .nth(1)
+        args.get(1)
             .expect("Please make sure the iso is the first argument."),
     );
     iso_path = iso_path.canonicalize()?;
+    let cwd = std::env::current_dir()?;
     // let new_path = std::env::current_exe()?.parent().unwrap();
     std::env::set_current_dir(std::env::current_exe()?.parent().unwrap())?;
+    let options = parse_build_options(&args[2..], &cwd)?;
 
-    extract_iso(&iso_path)?;
-    copy_eng()?;
-    remove_extraneous()?;
-    decrypt_eboot()?;
-    apply_misc_patches()?;
-    extract_cpk()?;
-    patch_event()?;
-    build_cpk()?;
-    build_iso(&iso_path)?;
-    cleanup()?;
+    let mut iso = ISO::from_file(File::open(&iso_path)?)?;
+    let variant = identify_source(&mut iso)?;
+    let mut overlay = Overlay::new();
+    copy_eng(&mut overlay);
+    remove_extraneous(&mut overlay);
+    let mut eboot = decrypt_eboot(&mut iso)?;
+    let param = apply_misc_patches(&mut iso, &mut eboot)?;
+    let cpk = build_cpk(&mut iso, &mut eboot)?;
+    overlay.replace(EBOOT_PATH, OverlayData::Memory(eboot));
+    overlay.replace(PARAM_PATH, OverlayData::Memory(param));
+    overlay.replace(CPK_PATH, OverlayData::Stream(Box::new(cpk)));
+    let checksums = build_iso(&mut iso, overlay, &options)?;
+    verify_output(&variant, &options, &checksums)?;
     println!("Done!");
 
     Ok(())